@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+
+use crate::cli::AnalyzeOpts;
+use crate::duration_to_human;
+use crate::record::{Sample, SampleReader};
+use crate::stat::StatSnapshot;
+use crate::summary;
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// a sample with its target id resolved to the name
+enum Event {
+    /// a client run began recording the target
+    Start,
+    Connected,
+    Failure,
+    Echo { rtt: Option<u64> },
+}
+
+/// first failure - a failed connection or a lost echo - to the next echo that came back
+struct Outage {
+    start: u64,
+    end: u64,
+    /// still going on at the last sample looked at
+    ongoing: bool,
+    failures: u64,
+    lost: u64,
+}
+
+impl Outage {
+    fn duration(&self) -> Duration {
+        Duration::from_nanos(self.end - self.start)
+    }
+}
+
+/// every sample in `files` that `opts` selects, by target and then in time order
+fn load(opts: &AnalyzeOpts) -> Result<BTreeMap<String, Vec<(u64, Event)>>> {
+    let from = opts.from.map(|t| t.timestamp_nanos_opt().unwrap_or(0) as u64).unwrap_or(0);
+    let to = opts.to.map(|t| t.timestamp_nanos_opt().unwrap_or(i64::MAX) as u64).unwrap_or(u64::MAX);
+    let mut targets: BTreeMap<String, Vec<(u64, Event)>> = BTreeMap::new();
+    for path in opts.files.iter() {
+        let mut names: BTreeMap<u32, String> = BTreeMap::new();
+        for sample in SampleReader::open(path)? {
+            let sample = sample?;
+            let time = sample.time();
+            let (id, event) = match sample {
+                Sample::Target { id, name, .. } => {
+                    names.insert(id, name);
+                    (id, Event::Start)
+                },
+                Sample::Connected { target, .. } => (target, Event::Connected),
+                Sample::ConnFailure { target, .. } => (target, Event::Failure),
+                Sample::Echo { target, rtt, .. } => (target, Event::Echo { rtt }),
+            };
+            let name = match names.get(&id) {
+                Some(name) => name,
+                None => continue,
+            };
+            if time < from || time >= to || opts.target.as_ref().is_some_and(|t| t != name) {
+                continue;
+            }
+            targets.entry(name.clone()).or_default().push((time, event));
+        }
+    }
+    // several files may cover the same target over overlapping times
+    targets.values_mut().for_each(|events| events.sort_by_key(|e| e.0));
+    Ok(targets)
+}
+
+/// totals, percentiles and outages over `events`
+fn summarize(events: &[(u64, Event)]) -> (StatSnapshot, Vec<Outage>) {
+    let mut snap = StatSnapshot::new();
+    let mut outages: Vec<Outage> = vec![];
+    let mut open: Option<Outage> = None;
+    let mut last = events.first().map(|e| e.0).unwrap_or(0);
+    for (time, event) in events.iter() {
+        let time = *time;
+        match event {
+            Event::Start => {
+                // the gap between two runs is not an outage of the network
+                if let Some(mut o) = open.take() {
+                    o.end = last;
+                    outages.push(o);
+                }
+            },
+            Event::Connected => snap.connects += 1,
+            Event::Failure => {
+                snap.conn_failures += 1;
+                open.get_or_insert(Outage { start: time, end: time, ongoing: false, failures: 0, lost: 0 }).failures += 1;
+            },
+            Event::Echo { rtt: None } => {
+                snap.sent += 1;
+                snap.lost += 1;
+                open.get_or_insert(Outage { start: time, end: time, ongoing: false, failures: 0, lost: 0 }).lost += 1;
+            },
+            Event::Echo { rtt: Some(rtt) } => {
+                snap.sent += 1;
+                snap.update(Duration::from_nanos(*rtt), None);
+                if let Some(mut o) = open.take() {
+                    o.end = time;
+                    outages.push(o);
+                }
+            },
+        }
+        last = time;
+    }
+    if let Some(mut o) = open.take() {
+        o.end = last;
+        o.ongoing = true;
+        outages.push(o);
+    }
+    snap.max_outage = outages.iter().map(|o| o.duration()).max().unwrap_or_default();
+    snap.outages = outages.len() as u64;
+    snap.outage_time = outages.iter().map(|o| o.duration()).sum();
+    let span = last - events.first().map(|e| e.0).unwrap_or(last);
+    if span > 0 {
+        snap.availability_pct = (100.0 * (1.0 - snap.outage_time.as_nanos() as f64 / span as f64)).clamp(0.0, 100.0);
+    }
+    (snap, outages)
+}
+
+/// `events` cut into slots `every` nanoseconds long counted from `origin` - the start of each
+/// slot that has events and those events.  Slots without any are left out
+fn slices(events: &[(u64, Event)], origin: u64, every: u64) -> Vec<(u64, &[(u64, Event)])> {
+    let mut out = vec![];
+    let mut start = 0;
+    while start < events.len() {
+        let slot = (events[start].0 - origin) / every;
+        let end = start + events[start..].iter().take_while(|e| (e.0 - origin) / every == slot).count();
+        out.push((origin + slot * every, &events[start..end]));
+        start = end;
+    }
+    out
+}
+
+fn time_str(nanos: u64) -> String {
+    Utc.timestamp_nanos(nanos as i64).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// print a summary per target, or per --every slice of it, then its outages
+pub fn run(opts: &AnalyzeOpts) -> Result<()> {
+    let targets = load(opts)?;
+    if targets.is_empty() {
+        println!("no samples in the time range and target given");
+    }
+    for (target, events) in targets.iter() {
+        let (first, last) = (events[0].0, events[events.len() - 1].0);
+        let (total, outages) = summarize(events);
+        if let Some(every) = opts.every {
+            let every = every.as_nanos().max(1) as u64;
+            let origin = opts.from.map(|t| t.timestamp_nanos_opt().unwrap_or(0) as u64).unwrap_or(first);
+            for (slot_from, slot_events) in slices(events, origin, every) {
+                let (snap, _) = summarize(slot_events);
+                summary::print(opts.human_time, &format!("{} {} to {}", target, time_str(slot_from), time_str(slot_from + every))
+                               , &snap, Duration::from_nanos(every));
+            }
+        } else {
+            summary::print(opts.human_time, &format!("{} {} to {}", target, time_str(first), time_str(last))
+                           , &total, Duration::from_nanos(last - first));
+        }
+        println!("--- {} outages: {} ---", target, outages.len());
+        for o in outages.iter() {
+            println!("{}  lasted {}{}  connection failures: {}  lost echos: {}"
+                     , time_str(o.start), if o.end == o.start { "0s".to_string() } else { duration_to_human(&o.duration(), 2) }
+                     , if o.ongoing { " and still going at the end" } else { "" }
+                     , o.failures, o.lost);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+    use crate::record::write_samples;
+    use super::*;
+
+    fn echo(rtt: Option<u64>) -> Event {
+        Event::Echo { rtt }
+    }
+
+    fn times(events: &[(u64, Event)]) -> Vec<u64> {
+        events.iter().map(|e| e.0).collect()
+    }
+
+    #[test]
+    fn outages_stop_at_a_restart_and_run_to_the_end() {
+        let events = vec![
+            (0, Event::Start),
+            (5, Event::Connected),
+            (10, echo(Some(2))),
+            (20, Event::Failure),
+            (30, echo(None)),
+            // the client was restarted - the time it was not running is no outage
+            (100, Event::Start),
+            (105, Event::Connected),
+            (110, echo(Some(4))),
+            (120, echo(None)),
+            (125, echo(None)),
+        ];
+        let (snap, outages) = summarize(&events);
+        assert_eq!((snap.sent, snap.echos, snap.lost, snap.connects, snap.conn_failures), (5, 2, 3, 2, 1));
+        let got: Vec<(u64, u64, bool, u64, u64)> = outages.iter().map(|o| (o.start, o.end, o.ongoing, o.failures, o.lost)).collect();
+        assert_eq!(got, vec![(20, 30, false, 1, 1), (120, 125, true, 0, 2)]);
+        assert_eq!((snap.outages, snap.outage_time, snap.max_outage), (2, Duration::from_nanos(15), Duration::from_nanos(10)));
+        // 15 of the 125 nanoseconds covered were spent in an outage
+        assert!((snap.availability_pct - 88.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn outage_ended_by_an_echo() {
+        let events = vec![(0, Event::Failure), (7, echo(None)), (9, echo(Some(1)))];
+        let (snap, outages) = summarize(&events);
+        assert_eq!(outages.len(), 1);
+        assert_eq!((outages[0].start, outages[0].end, outages[0].ongoing), (0, 9, false));
+        assert_eq!(snap.max_outage, Duration::from_nanos(9));
+    }
+
+    #[test]
+    fn every_slices_skip_empty_slots() {
+        let events: Vec<(u64, Event)> = [100, 105, 119, 150, 160].iter().map(|t| (*t, echo(Some(1)))).collect();
+        let got: Vec<(u64, Vec<u64>)> = slices(&events, 100, 20).into_iter().map(|(from, e)| (from, times(e))).collect();
+        assert_eq!(got, vec![(100, vec![100, 105, 119]), (140, vec![150]), (160, vec![160])]);
+    }
+
+    #[test]
+    fn load_names_targets_per_run_and_filters() {
+        let path = std::env::temp_dir().join(format!("netdelay-{}-analyze-load.ndr", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let echo = |time, target| Sample::Echo { time, target, conn_id: 1, seq: 0, rtt: Some(1) };
+        write_samples(&path, &[
+            Sample::Target { time: 10, id: 0, name: "a".to_string() },
+            Sample::Target { time: 10, id: 1, name: "b".to_string() },
+            echo(20, 0),
+            echo(30, 1),
+            // an echo for an id never declared is skipped
+            echo(35, 7),
+        ]);
+        // a second run appended to the file numbers its targets from 0 again
+        write_samples(&path, &[
+            Sample::Target { time: 40, id: 0, name: "b".to_string() },
+            echo(50, 0),
+        ]);
+        let mut opts = AnalyzeOpts::from_iter_safe(["analyze".as_ref(), path.as_os_str()].iter()).unwrap();
+        let all = load(&opts).unwrap();
+        assert_eq!(all.keys().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(times(&all["a"]), vec![10, 20]);
+        assert_eq!(times(&all["b"]), vec![10, 30, 40, 50]);
+
+        opts.from = Some(Utc.timestamp_nanos(30));
+        opts.to = Some(Utc.timestamp_nanos(50));
+        opts.target = Some("b".to_string());
+        let some = load(&opts).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(some.keys().collect::<Vec<_>>(), vec!["b"]);
+        assert_eq!(times(&some["b"]), vec![30, 40]);
+    }
+}
@@ -0,0 +1,119 @@
+use std::time::{Duration, Instant};
+
+use crate::cli::ClientOpts;
+
+/// how long to wait before the next connect to a target - grows with every failure in a row
+///
+/// each delay is cut by a random part of up to --break-jitter so a fleet of clients that lost
+/// the same server does not come back to it in lockstep
+pub struct Backoff {
+    initial: Duration,
+    multiplier: f64,
+    max: Duration,
+    jitter: f64,
+    /// a connection that lasted this long starts the next run of failures from `initial`
+    stable: Duration,
+    /// failures in a row
+    attempt: u32,
+    next: Duration,
+    connected_at: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(cli: &ClientOpts) -> Self {
+        Backoff {
+            initial: cli.break_time,
+            multiplier: cli.break_multiplier.max(1.0),
+            max: cli.break_max.max(cli.break_time),
+            jitter: cli.break_jitter.clamp(0.0, 1.0),
+            stable: cli.break_reset,
+            attempt: 0,
+            next: cli.break_time,
+            connected_at: None,
+        }
+    }
+
+    pub fn connected(&mut self) {
+        self.connected_at = Some(Instant::now());
+    }
+
+    /// count a failed connect or a lost connection - returns how many failed in a row and how long to wait before the next
+    pub fn failed(&mut self) -> (u32, Duration) {
+        if self.connected_at.take().is_some_and(|at| at.elapsed() >= self.stable) {
+            self.attempt = 0;
+            self.next = self.initial;
+        }
+        self.attempt += 1;
+        let delay = self.next;
+        // a product too large for a Duration is past max anyway
+        self.next = Duration::try_from_secs_f64(delay.as_secs_f64() * self.multiplier).map_or(self.max, |next| next.min(self.max));
+        let cut = Duration::try_from_secs_f64(delay.as_secs_f64() * self.jitter * rand::random::<f64>()).unwrap_or_default();
+        (self.attempt, delay.saturating_sub(cut))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn backoff(jitter: &str) -> Backoff {
+        let args = ["client", "127.0.0.1", "--break-time", "1s", "--break-multiplier", "2", "--break-max", "5s"
+            , "--break-jitter", jitter, "--break-reset", "60s"];
+        Backoff::new(&ClientOpts::from_iter_safe(args.iter()).unwrap())
+    }
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn grows_by_the_multiplier_up_to_max() {
+        let mut b = backoff("0");
+        let got: Vec<(u32, Duration)> = (0..5).map(|_| b.failed()).collect();
+        assert_eq!(got, vec![(1, secs(1)), (2, secs(2)), (3, secs(4)), (4, secs(5)), (5, secs(5))]);
+    }
+
+    #[test]
+    fn huge_multiplier_stops_at_max() {
+        let mut b = Backoff::new(&ClientOpts::from_iter_safe(["client", "127.0.0.1", "--break-multiplier", "1e300", "--break-max", "5s"
+            , "--break-jitter", "0"].iter()).unwrap());
+        let got: Vec<Duration> = (0..4).map(|_| b.failed().1).collect();
+        assert_eq!(got, vec![secs(1), secs(5), secs(5), secs(5)]);
+    }
+
+    #[test]
+    fn bad_multiplier_or_jitter_is_refused() {
+        for (option, value) in [("--break-multiplier", "NaN"), ("--break-multiplier", "inf"), ("--break-multiplier", "0.5")
+            , ("--break-jitter", "NaN"), ("--break-jitter", "-0.1"), ("--break-jitter", "1.5")] {
+            assert!(ClientOpts::from_iter_safe(["client", "127.0.0.1", option, value].iter()).is_err(), "{} {}", option, value);
+        }
+    }
+
+    #[test]
+    fn jitter_cuts_up_to_its_share() {
+        for _ in 0..100 {
+            let (_, delay) = backoff("0.25").failed();
+            assert!(delay > Duration::from_millis(750) && delay <= secs(1), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn short_connection_keeps_growing() {
+        let mut b = backoff("0");
+        b.failed();
+        b.failed();
+        b.connected();
+        assert_eq!(b.failed(), (3, secs(4)));
+    }
+
+    #[test]
+    fn connection_that_lasted_break_reset_starts_over() {
+        let mut b = backoff("0");
+        b.failed();
+        b.failed();
+        b.connected_at = Instant::now().checked_sub(secs(60));
+        assert_eq!(b.failed(), (1, secs(1)));
+        assert_eq!(b.failed(), (2, secs(2)));
+    }
+}
@@ -8,9 +8,14 @@ use std::time::{Instant, Duration};
 use log::LevelFilter;
 use humantime::parse_duration;
 use crate::util::str_to_socketaddr;
+use crate::sink::Format;
+use crate::otlp::{Endpoint, endpoint_from_str};
+use crate::push::{PushFormat, Transport, push_addr_from_str};
+use crate::target::TargetSpec;
 
 use crate::util::{to_log_level, to_duration, to_size_usize};
 use std::path::PathBuf;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 
 type Result<T> = anyhow::Result<T, anyhow::Error>;
 
@@ -25,13 +30,68 @@ version = BUILD_INFO.as_str(), rename_all = "kebab-case",
 global_settings(& [structopt::clap::AppSettings::ColoredHelp, structopt::clap::AppSettings::DeriveDisplayOrder]),
 )]
 pub struct Cli {
-    #[structopt(short, long, conflicts_with("client"))]
-    /// server mode - note ip:port binding address is optional
-    pub server: Option<Option<String>>,
+    #[structopt(short = "l", long, global = true, name="log_level", parse(try_from_str = to_log_level), conflicts_with("log_config"))]
+    /// log level
+    pub log_level: Option<LevelFilter>,
+
+    #[structopt(short = "L", long, global = true, name="log_config", conflicts_with("log_level"))]
+    /// use log4rs configuration file
+    pub log_config: Option<PathBuf>,
+
+    #[structopt(long, global = true, name="config", env = "NETDELAY_CONFIG")]
+    /// read options from this TOML or YAML file
+    ///
+    /// NETDELAY_<OPTION> environment variables override it, e.g. NETDELAY_PORT=5151, and the command line overrides both.
+    /// A flag they turn on is turned off with --no-<flag>, e.g. --no-udp
+    pub config: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    pub cmd: Cmd,
+}
+
+impl Cli {
+    /// a client --echo-out, --tick-out or --outage-out of - writes records to stdout
+    pub fn records_to_stdout(&self) -> bool {
+        match self.cmd {
+            Cmd::Client(ref opts) => [&opts.echo_out, &opts.tick_out, &opts.outage_out].iter()
+                .any(|p| p.as_deref() == Some(std::path::Path::new("-"))),
+            _ => false,
+        }
+    }
+}
 
-    #[structopt(short, long, conflicts_with("server"), parse(try_from_str = str_to_socketaddr))]
-    /// client ip:port of server end to connect too
-    pub client: Option<SocketAddr>,
+/// parsed once at start up so the size of ClientOpts does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(StructOpt, Debug, Clone)]
+pub enum Cmd {
+    /// echo back whatever clients send
+    Server(ServerOpts),
+    /// send echos to a server and report how long they took
+    Client(ClientOpts),
+    /// recompute summaries, percentiles and outages from client --record files
+    Analyze(AnalyzeOpts),
+    /// run a server and a client against it over loopback to check this build and host
+    Selftest(SelftestOpts),
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(rename_all = "kebab-case")]
+pub struct ServerOpts {
+    /// addresses to listen on as ip, ip:port or [ip6]:port - without a port --port is used
+    ///
+    /// give several to listen on all of them, each with its own accept thread.  Defaults to [::]
+    /// taking IPv4 and IPv6 clients alike, or 0.0.0.0 where there is no IPv6
+    pub binds: Vec<String>,
+
+    #[structopt(long)]
+    /// IPv6 listeners take IPv6 clients only
+    ///
+    /// already the case for an IPv6 listener on a port that an IPv4 address is also given for
+    pub v6_only: bool,
+
+    #[structopt(short, long)]
+    /// echo over udp datagrams instead of a tcp stream - clients must use --udp too
+    pub udp: bool,
 
     #[structopt(short, long, default_value("15s"), parse(try_from_str = dur_from_str))]
     /// timeout for tcp socket
@@ -41,12 +101,94 @@ pub struct Cli {
     /// port default to 5150 but this overrides that
     pub port: u16,
 
+    #[structopt(short, long, parse(try_from_str = dur_from_str))]
+    /// how long to wait before each reply - tcp only
+    ///
+    /// examples: 5 = 5 seconds,  1s = 1 second, 100ms500us
+    pub interval: Option<Duration>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(rename_all = "kebab-case")]
+pub struct ClientOpts {
+    #[structopt(required = true)]
+    /// server ends to connect too as [name=]ip:port[,interval=..,info-threshold=..,warn-threshold=..]
+    ///
+    /// each target gets its own connection, stats and ticker line.  The name tags its logs,
+    /// records and metrics and defaults to ip:port
+    pub targets: Vec<TargetSpec>,
+
+    #[structopt(short, long)]
+    /// echo over udp datagrams instead of a tcp stream - both ends must agree
+    ///
+    /// client counts echos not returned within the socket timeout as lost and
+    /// replies that show up after that as late
+    pub udp: bool,
+
+    #[structopt(long, default_value("0"), parse(try_from_str = payload_size_from_str))]
+    /// bytes of padding added to each echo - at most 16M
+    ///
+    /// examples: 64, 1k, 16K
+    pub payload_size: usize,
+
+    #[structopt(long, parse(try_from_str = sweep_from_str))]
+    /// run echos over a range of payload sizes doubling from MIN up to MAX then print a latency by size table
+    ///
+    /// given as MIN-MAX e.g. 64-64k
+    pub sweep: Option<(usize, usize)>,
+
+    #[structopt(long, default_value("100"))]
+    /// number of echos per payload size in a --sweep
+    pub sweep_count: u64,
+
+    #[structopt(short, long, default_value("15s"), parse(try_from_str = dur_from_str))]
+    /// timeout for tcp socket
+    pub timeout_socket: Duration,
+
+    #[structopt(long)]
+    /// keep the connection when an echo is not returned within the socket timeout - count it as
+    /// lost and reconnect only after this many in a row
+    ///
+    /// replies that show up later are matched by sequence number and counted as late.  Without
+    /// this a tcp client reconnects on the first timeout and a udp client never does
+    pub reconnect_after: Option<u32>,
+
+    #[structopt(short, long, default_value("5150"))]
+    /// port default to 5150 but this overrides that
+    pub port: u16,
+
     #[structopt(short="T", long, parse(try_from_str = dur_from_str))]
     /// ticker interval
     ///
     /// examples: 5 = 5 seconds,  1s = 1 second, 100ms500us
     pub ticker_interval: Option<Duration>,
 
+    #[structopt(short = "n", long)]
+    /// stop after sending this many echos and print a summary
+    ///
+    /// a connect that fails counts as one too so a target that is down does not keep the run going
+    pub count: Option<u64>,
+
+    #[structopt(short = "w", long, parse(try_from_str = dur_from_str))]
+    /// stop after running this long and print a summary
+    ///
+    /// examples: 30s, 10m
+    pub duration: Option<Duration>,
+
+    #[structopt(long, parse(try_from_str = dur_from_str))]
+    /// exit with code 2 if the run's p99 echo time is over this
+    ///
+    /// SLA exit codes are OR'd: 2 p99, 4 loss, 8 outage, 16 no echos at all - 1 is any other error
+    pub max_p99: Option<Duration>,
+
+    #[structopt(long)]
+    /// exit with code 4 if more than this percent of echos are lost
+    pub max_loss: Option<f64>,
+
+    #[structopt(long, parse(try_from_str = dur_from_str))]
+    /// exit with code 8 if any outage - failure to next returned echo - lasts longer than this
+    pub max_outage: Option<Duration>,
+
     #[structopt(short, long, parse(try_from_str = dur_from_str))]
     /// how to wait between tcp pings
     ///
@@ -61,21 +203,158 @@ pub struct Cli {
     /// warn level logging threshold for turn-around time
     pub warn_threshold: Duration,
 
-    #[structopt(short = "l", long, name="log_level", parse(try_from_str = to_log_level), conflicts_with("log_config"))]
-    /// log level
-    pub log_level: Option<LevelFilter>,
+    #[structopt(short = "H", long)]
+    /// write stats using 1s500ms888us format instead of float milliseconds
+    pub human_time: bool,
 
-    #[structopt(short = "L", long, name="log_config", conflicts_with("log_level"))]
-    /// use log4rs configuration file
-    pub log_config: Option<PathBuf>,
+    #[structopt(long)]
+    /// report forward and return one-way delays in the ticker
+    ///
+    /// only meaningful when client and server clocks are synchronised, e.g. by NTP or PTP
+    pub one_way: bool,
+
+    #[structopt(long)]
+    /// estimate the server's clock offset NTP style and correct one-way delays by it
+    ///
+    /// the offset comes from the echo with the lowest round trip in the window and
+    /// its error bound is half that round trip.  Implies --one-way
+    pub clock_offset: bool,
+
+    #[structopt(long, default_value("64"))]
+    /// number of recent echos the clock offset is estimated over
+    pub offset_window: usize,
+
+    #[structopt(long, default_value("1ms"), parse(try_from_str = dur_from_str))]
+    /// warn when the clock offset jitter or error bound goes over this
+    pub offset_unstable: Duration,
+
+    #[structopt(long)]
+    /// write a record per echo - time, target, connection id, seq and rtt in ns - to this file or - for stdout
+    pub echo_out: Option<PathBuf>,
+
+    #[structopt(long, default_value("jsonl"))]
+    /// format of --echo-out: jsonl or csv
+    pub echo_format: Format,
+
+    #[structopt(long)]
+    /// write a record per ticker interval - start, end, echos, rate, min/avg/max, percentiles and loss - to this file or - for stdout
+    ///
+    /// needs --ticker-interval
+    pub tick_out: Option<PathBuf>,
+
+    #[structopt(long, default_value("jsonl"))]
+    /// format of --tick-out: jsonl or csv
+    pub tick_format: Format,
+
+    #[structopt(long)]
+    /// write a record per outage - start, end, target, duration in ns, cause, connection failures and lost echos - to this file or - for stdout
+    ///
+    /// an outage runs from the first failure to the next echo that comes back
+    pub outage_out: Option<PathBuf>,
+
+    #[structopt(long, default_value("jsonl"))]
+    /// format of --outage-out: jsonl or csv
+    pub outage_format: Format,
+
+    #[structopt(long, parse(try_from_str = str_to_socketaddr))]
+    /// serve Prometheus metrics - echo time histogram plus echo, reconnect and error counters per target - on http://ip:port/metrics
+    pub metrics_addr: Option<SocketAddr>,
+
+    #[structopt(long, parse(try_from_str = push_addr_from_str))]
+    /// push every ticker interval to a collector at host:port - needs --ticker-interval
+    pub push_addr: Option<SocketAddr>,
+
+    #[structopt(long, default_value("influx"))]
+    /// format pushed to --push-addr: influx (line protocol), statsd or graphite (plaintext)
+    pub push_format: PushFormat,
+
+    #[structopt(long, default_value("udp"))]
+    /// protocol used to reach --push-addr: udp or tcp
+    pub push_proto: Transport,
+
+    #[structopt(long, parse(try_from_str = endpoint_from_str))]
+    /// export the run's echo time histogram and counters to an OTLP/HTTP collector every ticker interval
+    ///
+    /// given as http://host[:port][/path] - port defaults to 4318 and path to /v1/metrics
+    pub otlp_endpoint: Option<Endpoint>,
+
+    #[structopt(long)]
+    /// client name pushed stats are tagged with and OTLP host.name - defaults to the host name
+    pub client_name: Option<String>,
+
+    #[structopt(long)]
+    /// append every raw sample - echos, losses, connects and connection failures - to this binary file
+    ///
+    /// read it back with the analyze subcommand
+    pub record: Option<PathBuf>,
+
+    #[structopt(short = "B", long, default_value("1s"), parse(try_from_str = dur_from_str))]
+    /// break time after the first error trying to setup or RE-setup a connection
+    ///
+    /// each further error in a row waits --break-multiplier times longer, up to --break-max
+    pub break_time: Duration,
+
+    #[structopt(long, default_value("2"), parse(try_from_str = multiplier_from_str))]
+    /// how much longer each break is than the one before while errors keep coming - 1 or more
+    pub break_multiplier: f64,
+
+    #[structopt(long, default_value("60s"), parse(try_from_str = dur_from_str))]
+    /// longest break between connection attempts
+    pub break_max: Duration,
+
+    #[structopt(long, default_value("0.25"), parse(try_from_str = fraction_from_str))]
+    /// cut each break by a random part of up to this fraction of it so clients do not reconnect in lockstep - 0 to 1
+    pub break_jitter: f64,
+
+    #[structopt(long, default_value("60s"), parse(try_from_str = dur_from_str))]
+    /// a connection that lasts this long starts breaks over from --break-time
+    pub break_reset: Duration,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(rename_all = "kebab-case")]
+pub struct AnalyzeOpts {
+    #[structopt(long, parse(try_from_str = time_from_str))]
+    /// only samples at or after this time - RFC 3339 or YYYY-MM-DD HH:MM:SS in UTC
+    pub from: Option<DateTime<Utc>>,
+
+    #[structopt(long, parse(try_from_str = time_from_str))]
+    /// only samples before this time - RFC 3339 or YYYY-MM-DD HH:MM:SS in UTC
+    pub to: Option<DateTime<Utc>>,
+
+    #[structopt(long)]
+    /// only samples of this target
+    pub target: Option<String>,
+
+    #[structopt(long, parse(try_from_str = dur_from_str))]
+    /// a summary per slice of this long instead of one for the whole range
+    ///
+    /// examples: 10m, 3600s
+    pub every: Option<Duration>,
 
     #[structopt(short = "H", long)]
     /// write stats using 1s500ms888us format instead of float milliseconds
     pub human_time: bool,
 
-    #[structopt(short = "B", long, default_value("60s"), parse(try_from_str = dur_from_str))]
-    /// break time if there are error trying to setup or RE-setup connections
-    pub break_time: Duration,
+    #[structopt(required = true)]
+    /// recordings made with client --record
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(rename_all = "kebab-case")]
+pub struct SelftestOpts {
+    #[structopt(short, long)]
+    /// echo over udp datagrams instead of a tcp stream
+    pub udp: bool,
+
+    #[structopt(short = "n", long, default_value("100"))]
+    /// number of echos to send
+    pub count: u64,
+
+    #[structopt(long, default_value("0"), parse(try_from_str = payload_size_from_str))]
+    /// bytes of padding added to each echo - at most 16M
+    pub payload_size: usize,
 }
 
 
@@ -86,7 +365,7 @@ pub fn dur_from_str(s: &str) -> Result<Duration> {
     let mut list = vec![];
     let mut have_unit = false;
     for c in s.chars() {
-        if c >= '0' && c <= '9' {
+        if c.is_ascii_digit() {
             if have_unit {
                 list.push((num.clone(), unit.clone()));
                 num.clear();
@@ -116,3 +395,50 @@ pub fn dur_from_str(s: &str) -> Result<Duration> {
     }
     Ok(Duration::from_nanos(tot))
 }
+
+pub fn time_from_str(s: &str) -> Result<DateTime<Utc>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    for fmt in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(t) = NaiveDateTime::parse_from_str(s, fmt) {
+            return Ok(Utc.from_utc_datetime(&t));
+        }
+    }
+    Err(anyhow!("time \"{}\" must be RFC 3339 like 2021-06-30T12:00:00Z or YYYY-MM-DD HH:MM:SS in UTC", s))
+}
+
+/// largest --payload-size - servers drop a connection whose echo claims more so a bad
+/// length can not make them allocate without bound
+pub const MAX_PAYLOAD_SIZE: usize = 16 << 20;
+
+pub fn payload_size_from_str(s: &str) -> Result<usize> {
+    let size = to_size_usize(s)?;
+    if size > MAX_PAYLOAD_SIZE {
+        return Err(anyhow!("payload size {} is over the largest of {} bytes", s, MAX_PAYLOAD_SIZE));
+    }
+    Ok(size)
+}
+
+pub fn multiplier_from_str(s: &str) -> Result<f64> {
+    match s.parse::<f64>() {
+        Ok(m) if m.is_finite() && m >= 1.0 => Ok(m),
+        _ => Err(anyhow!("multiplier \"{}\" must be a number of 1 or more", s)),
+    }
+}
+
+pub fn fraction_from_str(s: &str) -> Result<f64> {
+    match s.parse::<f64>() {
+        Ok(f) if (0.0..=1.0).contains(&f) => Ok(f),
+        _ => Err(anyhow!("fraction \"{}\" must be a number from 0 to 1", s)),
+    }
+}
+
+pub fn sweep_from_str(s: &str) -> Result<(usize, usize)> {
+    let (min, max) = s.split_once('-').ok_or_else(|| anyhow!("sweep range \"{}\" must be MIN-MAX like 64-64k", s))?;
+    let (min, max) = (payload_size_from_str(min)?, payload_size_from_str(max)?);
+    if min > max {
+        return Err(anyhow!("sweep range \"{}\" has MIN larger than MAX", s));
+    }
+    Ok((min, max))
+}
@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+
+/// one echo worth of NTP on-wire values in nanoseconds
+#[derive(Clone, Copy, Debug)]
+struct OffsetSample {
+    /// theta - ((t2 - t1) + (t3 - t4)) / 2, server clock minus client clock
+    offset: i64,
+    /// delta - (t4 - t1) - (t3 - t2), round trip minus server hold time
+    delay: i64,
+}
+
+/// current best guess of server clock minus client clock
+#[derive(Clone, Copy, Debug)]
+pub struct ClockOffset {
+    pub offset_nanos: i64,
+    /// half the round trip delay of the sample the offset came from - the true offset is within +/- this
+    pub error_nanos: i64,
+    /// standard deviation of every offset in the window - large means the estimate is not to be trusted
+    pub jitter_nanos: i64,
+    pub samples: usize,
+}
+
+/// NTP style clock filter over the last `window` echos
+///
+/// like NTP the sample with the lowest round trip delay gives the offset since
+/// it has the least room for path asymmetry to skew it
+pub struct OffsetEstimator {
+    window: usize,
+    samples: VecDeque<OffsetSample>,
+}
+
+impl OffsetEstimator {
+    pub fn new(window: usize) -> Self {
+        OffsetEstimator {
+            window: window.max(1),
+            samples: VecDeque::with_capacity(window.max(1)),
+        }
+    }
+
+    /// t1 client send, t2 server receive, t3 server send, t4 client receive
+    pub fn add(&mut self, t1: u64, t2: u64, t3: u64, t4: u64) {
+        let (t1, t2, t3, t4) = (t1 as i64, t2 as i64, t3 as i64, t4 as i64);
+        let sample = OffsetSample {
+            offset: ((t2 - t1) + (t3 - t4)) / 2,
+            delay: (t4 - t1) - (t3 - t2),
+        };
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn estimate(&self) -> Option<ClockOffset> {
+        let best = self.samples.iter().min_by_key(|s| s.delay)?;
+        let n = self.samples.len() as f64;
+        let mean = self.samples.iter().map(|s| s.offset as f64).sum::<f64>() / n;
+        let var = self.samples.iter().map(|s| (s.offset as f64 - mean).powi(2)).sum::<f64>() / n;
+        Some(ClockOffset {
+            offset_nanos: best.offset,
+            error_nanos: best.delay.max(0) / 2,
+            jitter_nanos: var.sqrt() as i64,
+            samples: self.samples.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an echo sent at `t1` over a path of `fwd` then `ret` nanoseconds to a server whose clock is
+    /// `offset` ahead that holds it for `hold`
+    fn echo(est: &mut OffsetEstimator, t1: u64, offset: i64, fwd: u64, hold: u64, ret: u64) {
+        let t2 = (t1 as i64 + fwd as i64 + offset) as u64;
+        let t3 = t2 + hold;
+        let t4 = t1 + fwd + hold + ret;
+        est.add(t1, t2, t3, t4);
+    }
+
+    #[test]
+    fn empty_has_no_estimate() {
+        assert!(OffsetEstimator::new(8).estimate().is_none());
+    }
+
+    #[test]
+    fn server_ahead_is_positive_and_behind_negative() {
+        let mut est = OffsetEstimator::new(8);
+        echo(&mut est, 1_000_000, 5_000, 100, 50, 100);
+        let off = est.estimate().unwrap();
+        assert_eq!(off.offset_nanos, 5_000);
+        // round trip less the server hold time, halved
+        assert_eq!(off.error_nanos, 100);
+        assert_eq!(off.jitter_nanos, 0);
+
+        let mut est = OffsetEstimator::new(8);
+        echo(&mut est, 1_000_000, -5_000, 100, 50, 100);
+        assert_eq!(est.estimate().unwrap().offset_nanos, -5_000);
+    }
+
+    #[test]
+    fn asymmetric_path_skews_offset_by_half_the_difference() {
+        let mut est = OffsetEstimator::new(8);
+        echo(&mut est, 1_000_000, 0, 300, 0, 100);
+        let off = est.estimate().unwrap();
+        assert_eq!(off.offset_nanos, 100);
+        assert_eq!(off.error_nanos, 200);
+    }
+
+    #[test]
+    fn lowest_delay_sample_gives_the_offset() {
+        let mut est = OffsetEstimator::new(8);
+        echo(&mut est, 1_000_000, 1_000, 500, 0, 100);
+        echo(&mut est, 2_000_000, 1_000, 50, 0, 50);
+        echo(&mut est, 3_000_000, 1_000, 100, 0, 400);
+        let off = est.estimate().unwrap();
+        assert_eq!(off.offset_nanos, 1_000);
+        assert_eq!(off.error_nanos, 50);
+        assert_eq!(off.samples, 3);
+        assert!(off.jitter_nanos > 0);
+    }
+
+    #[test]
+    fn window_drops_the_oldest_sample() {
+        let mut est = OffsetEstimator::new(2);
+        echo(&mut est, 1_000_000, 7_000, 10, 0, 10);
+        echo(&mut est, 2_000_000, 1_000, 100, 0, 100);
+        echo(&mut est, 3_000_000, 1_000, 200, 0, 200);
+        let off = est.estimate().unwrap();
+        assert_eq!(off.samples, 2);
+        assert_eq!(off.offset_nanos, 1_000);
+        assert_eq!(off.error_nanos, 100);
+    }
+}
@@ -0,0 +1,524 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context};
+use serde_json::{Map, Value};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// NETDELAY_<OPTION> environment variables override the config file, e.g. NETDELAY_PORT=5151
+const ENV_PREFIX: &str = "NETDELAY_";
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Kind {
+    Flag,
+    Value,
+}
+
+/// an option's long name, short letter and whether it takes a value
+type Opt = (&'static str, Option<char>, Kind);
+
+/// global options, given before or after the subcommand
+const GLOBALS: &[Opt] = &[("log-level", Some('l'), Kind::Value), ("log-config", Some('L'), Kind::Value), ("config", None, Kind::Value)];
+
+// the options of each subcommand as cli.rs declares them - a test keeps the two in step
+const SERVER: &[Opt] = &[
+    ("v6-only", None, Kind::Flag),
+    ("udp", Some('u'), Kind::Flag),
+    ("timeout-socket", Some('t'), Kind::Value),
+    ("port", Some('p'), Kind::Value),
+    ("interval", Some('i'), Kind::Value),
+];
+
+const CLIENT: &[Opt] = &[
+    ("udp", Some('u'), Kind::Flag),
+    ("payload-size", None, Kind::Value),
+    ("sweep", None, Kind::Value),
+    ("sweep-count", None, Kind::Value),
+    ("timeout-socket", Some('t'), Kind::Value),
+    ("reconnect-after", None, Kind::Value),
+    ("port", Some('p'), Kind::Value),
+    ("ticker-interval", Some('T'), Kind::Value),
+    ("count", Some('n'), Kind::Value),
+    ("duration", Some('w'), Kind::Value),
+    ("max-p99", None, Kind::Value),
+    ("max-loss", None, Kind::Value),
+    ("max-outage", None, Kind::Value),
+    ("interval", Some('i'), Kind::Value),
+    ("info-threshold", None, Kind::Value),
+    ("warn-threshold", None, Kind::Value),
+    ("human-time", Some('H'), Kind::Flag),
+    ("one-way", None, Kind::Flag),
+    ("clock-offset", None, Kind::Flag),
+    ("offset-window", None, Kind::Value),
+    ("offset-unstable", None, Kind::Value),
+    ("echo-out", None, Kind::Value),
+    ("echo-format", None, Kind::Value),
+    ("tick-out", None, Kind::Value),
+    ("tick-format", None, Kind::Value),
+    ("outage-out", None, Kind::Value),
+    ("outage-format", None, Kind::Value),
+    ("metrics-addr", None, Kind::Value),
+    ("push-addr", None, Kind::Value),
+    ("push-format", None, Kind::Value),
+    ("push-proto", None, Kind::Value),
+    ("otlp-endpoint", None, Kind::Value),
+    ("client-name", None, Kind::Value),
+    ("record", None, Kind::Value),
+    ("break-time", Some('B'), Kind::Value),
+    ("break-multiplier", None, Kind::Value),
+    ("break-max", None, Kind::Value),
+    ("break-jitter", None, Kind::Value),
+    ("break-reset", None, Kind::Value),
+];
+
+const ANALYZE: &[Opt] = &[
+    ("from", None, Kind::Value),
+    ("to", None, Kind::Value),
+    ("target", None, Kind::Value),
+    ("every", None, Kind::Value),
+    ("human-time", Some('H'), Kind::Flag),
+];
+
+const SELFTEST: &[Opt] = &[
+    ("udp", Some('u'), Kind::Flag),
+    ("count", Some('n'), Kind::Value),
+    ("payload-size", None, Kind::Value),
+];
+
+/// the options of subcommand `cmd`, None if there is no such subcommand
+fn options(cmd: &str) -> Option<&'static [Opt]> {
+    match cmd {
+        "server" => Some(SERVER),
+        "client" => Some(CLIENT),
+        "analyze" => Some(ANALYZE),
+        "selftest" => Some(SELFTEST),
+        _ => None,
+    }
+}
+
+/// config keys that give the subcommand's positional arguments
+fn positionals(cmd: &str) -> &'static [&'static str] {
+    match cmd {
+        "server" => &["bind", "binds"],
+        "client" => &["target", "targets"],
+        "analyze" => &["files"],
+        _ => &[],
+    }
+}
+
+/// whether `--key` is a flag or takes a value, None if `cmd` has no such option
+fn kind(cmd: &str, key: &str) -> Option<Kind> {
+    options(cmd)?.iter().find(|(long, _, _)| *long == key).map(|(_, _, kind)| *kind)
+}
+
+/// config keys may be snake_case like the struct fields or kebab-case like the options
+fn kebab(key: &str) -> String {
+    key.replace('_', "-")
+}
+
+fn is_global(key: &str) -> bool {
+    GLOBALS.iter().any(|(long, _, _)| *long == key && key != "config")
+}
+
+/// what the command line gave after the subcommand
+struct Given {
+    /// long names of the options given, a flag turned off with `--no-<flag>` included
+    options: Vec<String>,
+    /// whether any positional argument was given
+    positional: bool,
+    /// the arguments less the `--no-<flag>`s, which clap does not know
+    args: Vec<OsString>,
+}
+
+/// walk the arguments after subcommand `cmd`, skipping option values, to see what was given
+///
+/// anything not in the tables, like --help or a typo, is passed on for clap to deal with
+fn given(cmd: &str, args: &[OsString]) -> Given {
+    let opts = || GLOBALS.iter().chain(options(cmd).unwrap_or_default().iter());
+    let mut out = Given { options: vec![], positional: false, args: vec![] };
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].to_string_lossy().to_string();
+        i += 1;
+        if arg == "--" {
+            out.positional |= i < args.len();
+            out.args.extend(args[i - 1..].iter().cloned());
+            break;
+        }
+        let mut skip_value = false;
+        if let Some(long) = arg.strip_prefix("--") {
+            let (name, inline) = match long.split_once('=') {
+                Some((name, _)) => (name, true),
+                None => (long, false),
+            };
+            if let Some(flag) = name.strip_prefix("no-").filter(|f| kind(cmd, f) == Some(Kind::Flag)) {
+                out.options.push(flag.to_string());
+                continue;
+            }
+            skip_value = !inline && opts().any(|(l, _, k)| *l == name && *k == Kind::Value);
+            out.options.push(name.to_string());
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            // a cluster of short flags, the last of which may take a value joined on or as the next argument
+            for (at, c) in arg.char_indices().skip(1) {
+                match opts().find(|(_, s, _)| *s == Some(c)) {
+                    Some((long, _, kind)) => {
+                        out.options.push(long.to_string());
+                        if *kind == Kind::Value {
+                            skip_value = at + c.len_utf8() == arg.len();
+                            break;
+                        }
+                    },
+                    None => break,
+                }
+            }
+        } else {
+            out.positional = true;
+        }
+        out.args.push(args[i - 1].clone());
+        if skip_value && i < args.len() {
+            out.args.push(args[i].clone());
+            i += 1;
+        }
+    }
+    out
+}
+
+/// the command line with options from --config and NETDELAY_* environment variables added in
+///
+/// the command line wins over the environment which wins over the file.  The file's
+/// top level holds global options plus `command`, the subcommand to run when none is
+/// given, and a table per subcommand holds that subcommand's options
+pub fn args() -> Result<Vec<OsString>> {
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    let env: Vec<(String, String)> = std::env::vars()
+        .filter_map(|(k, v)| k.strip_prefix(ENV_PREFIX).map(|k| (k.to_ascii_lowercase(), v)))
+        .filter(|(k, _)| k != "config")
+        .collect();
+    let config = argv.iter().zip(argv.iter().skip(1))
+        .find_map(|(opt, val)| if opt == "--config" { Some(PathBuf::from(val)) } else { None })
+        .or_else(|| argv.iter().find_map(|a| a.to_str().and_then(|a| a.strip_prefix("--config=")).map(PathBuf::from)))
+        .or_else(|| std::env::var_os("NETDELAY_CONFIG").map(PathBuf::from));
+    let file = match config {
+        Some(ref path) => load(path)?,
+        None => Map::new(),
+    };
+    merge(argv, &file, env)
+}
+
+/// `argv` with the options from config `file` and the lowercased `env` variables added in
+fn merge(argv: Vec<OsString>, file: &Map<String, Value>, env: Vec<(String, String)>) -> Result<Vec<OsString>> {
+    // the subcommand is the first argument that is neither a global option nor its value
+    let mut sub_at = argv.len();
+    let mut i = 1;
+    while i < argv.len() {
+        let arg = argv[i].to_str().unwrap_or_default();
+        if GLOBALS.iter().any(|(long, short, _)| arg == format!("--{}", long) || short.is_some_and(|s| arg == format!("-{}", s))) {
+            i += 2;
+            continue;
+        } else if !arg.starts_with('-') {
+            sub_at = i;
+            break;
+        }
+        i += 1;
+    }
+    let cmd = match argv.get(sub_at) {
+        Some(cmd) => cmd.to_string_lossy().to_string(),
+        None => match file.get("command") {
+            Some(Value::String(cmd)) => cmd.clone(),
+            _ => return Ok(argv),
+        },
+    };
+    if options(&cmd).is_none() {
+        // help or a typo - let clap say what is wrong
+        return Ok(argv);
+    }
+
+    let mut globals: BTreeMap<String, Value> = BTreeMap::new();
+    let mut options: BTreeMap<String, Value> = BTreeMap::new();
+    for (key, value) in file.iter() {
+        if is_global(&kebab(key)) {
+            globals.insert(kebab(key), value.clone());
+        }
+    }
+    if let Some(Value::Object(section)) = file.get(&cmd) {
+        for (key, value) in section.iter() {
+            options.insert(kebab(key), value.clone());
+        }
+    }
+    // the environment is shared with other programs so variables that are not options are left alone
+    for (key, value) in env.into_iter() {
+        let key = kebab(&key);
+        if is_global(&key) {
+            globals.insert(key, Value::String(value));
+        } else if positionals(&cmd).contains(&key.as_str()) || kind(&cmd, &key).is_some() {
+            options.insert(key, Value::String(value));
+        }
+    }
+
+    // options given on the command line are not taken from the file or environment
+    let before = given("", &argv[1..sub_at.min(argv.len())]);
+    let user = given(&cmd, argv.get(sub_at + 1..).unwrap_or_default());
+    if before.options.iter().chain(user.options.iter()).any(|o| is_global(o)) {
+        // --log-level and --log-config conflict so either one given replaces both
+        globals.clear();
+    }
+    let mut positional: Vec<String> = vec![];
+    if !user.positional {
+        for key in positionals(&cmd) {
+            if let Some(value) = options.get(*key) {
+                positional.extend(render(value)?);
+            }
+        }
+    }
+
+    let mut out: Vec<OsString> = vec![argv[0].clone()];
+    for (key, value) in globals.iter() {
+        out.extend(render(value)?.into_iter().map(|v| OsString::from(format!("--{}={}", key, v))));
+    }
+    out.extend(before.args);
+    out.push(OsString::from(&cmd));
+    for (key, value) in options.iter() {
+        if positionals(&cmd).contains(&key.as_str()) || user.options.contains(key) {
+            continue;
+        }
+        match kind(&cmd, key) {
+            Some(Kind::Flag) => if flag(value).with_context(|| format!("{} option {} from the config file or environment", cmd, key))? {
+                out.push(OsString::from(format!("--{}", key)));
+            },
+            Some(Kind::Value) => {
+                out.extend(render(value)?.into_iter().map(|v| OsString::from(format!("--{}={}", key, v))));
+            },
+            None => return Err(anyhow!("{} has no option \"{}\"", cmd, key)),
+        }
+    }
+    out.extend(user.args);
+    out.extend(positional.into_iter().map(OsString::from));
+    Ok(out)
+}
+
+/// read a .toml or .yaml/.yml file - anything else is tried as toml then yaml - and check its keys
+fn load(path: &Path) -> Result<Map<String, Value>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("reading config file {}", path.display()))?;
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+    let value: Value = match ext.as_str() {
+        "toml" => toml::from_str(&text).with_context(|| format!("parsing config file {}", path.display()))?,
+        "yaml" | "yml" => serde_yaml::from_str(&text).with_context(|| format!("parsing config file {}", path.display()))?,
+        _ => toml::from_str(&text).or_else(|_| serde_yaml::from_str(&text))
+            .with_context(|| format!("parsing config file {} as toml or yaml", path.display()))?,
+    };
+    let map = match value {
+        Value::Object(map) => map,
+        Value::Null => Map::new(),
+        _ => return Err(anyhow!("config file {} must be a table of options", path.display())),
+    };
+    for (key, value) in map.iter() {
+        let key = kebab(key);
+        if is_global(&key) || key == "command" {
+            continue;
+        }
+        let section = match value {
+            Value::Object(section) if options(&key).is_some() => section,
+            _ => return Err(anyhow!("config file {} has unknown option \"{}\" - expected log_level, log_config, command or a server, client, analyze or selftest table"
+                                    , path.display(), key)),
+        };
+        for option in section.keys().map(|k| kebab(k)) {
+            if !positionals(&key).contains(&option.as_str()) && kind(&key, &option).is_none() {
+                return Err(anyhow!("config file {} has unknown {} option \"{}\"", path.display(), key, option));
+            }
+        }
+    }
+    Ok(map)
+}
+
+/// option values as given on the command line - a list gives the option once per item
+fn render(value: &Value) -> Result<Vec<String>> {
+    Ok(match value {
+        Value::String(s) => vec![s.clone()],
+        Value::Number(n) => vec![n.to_string()],
+        Value::Bool(b) => vec![b.to_string()],
+        Value::Array(items) => {
+            let mut out = vec![];
+            for item in items.iter() {
+                out.extend(render(item)?);
+            }
+            out
+        },
+        Value::Object(target) => vec![target_spec(target)?],
+        Value::Null => vec![],
+    })
+}
+
+/// a target table - name, addr and per target overrides - as `[name=]addr[,key=value...]`
+fn target_spec(target: &Map<String, Value>) -> Result<String> {
+    let addr = match target.get("addr") {
+        Some(Value::String(addr)) => addr,
+        _ => return Err(anyhow!("target {} needs an addr", Value::Object(target.clone()))),
+    };
+    let mut spec = match target.get("name") {
+        Some(Value::String(name)) => format!("{}={}", name, addr),
+        _ => addr.clone(),
+    };
+    for (key, value) in target.iter().filter(|(k, _)| *k != "addr" && *k != "name") {
+        for v in render(value)? {
+            spec.push_str(&format!(",{}={}", kebab(key), v));
+        }
+    }
+    Ok(spec)
+}
+
+fn flag(value: &Value) -> Result<bool> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        Value::String(s) => match s.to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" | "on" => Ok(true),
+            "false" | "0" | "no" | "off" | "" => Ok(false),
+            _ => Err(anyhow!("\"{}\" is not true or false", s)),
+        },
+        _ => Err(anyhow!("{} is not true or false", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+    use crate::cli::{AnalyzeOpts, Cli, ClientOpts, Cmd, SelftestOpts, ServerOpts};
+
+    /// the options clap lists in the long help of a subcommand, help and version left out
+    fn help_options(mut app: structopt::clap::App) -> Vec<(String, Option<char>, Kind)> {
+        let mut help = vec![];
+        app.write_long_help(&mut help).unwrap();
+        let mut out = vec![];
+        for line in String::from_utf8(help).unwrap().lines()
+            .filter(|l| l.trim_start().starts_with('-') && l.len() - l.trim_start().len() <= 8) {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let short = words[0].strip_suffix(',').and_then(|s| s.strip_prefix('-')).and_then(|s| s.chars().next());
+            let at = words.iter().position(|w| w.starts_with("--")).unwrap();
+            let long = words[at].trim_start_matches("--");
+            if long == "help" || long == "version" {
+                continue;
+            }
+            let kind = if words.get(at + 1).is_some_and(|w| w.starts_with('<')) { Kind::Value } else { Kind::Flag };
+            out.push((long.to_string(), short, kind));
+        }
+        out.sort();
+        out
+    }
+
+    fn sorted(opts: &[Opt]) -> Vec<(String, Option<char>, Kind)> {
+        let mut opts: Vec<_> = opts.iter().map(|(long, short, kind)| (long.to_string(), *short, *kind)).collect();
+        opts.sort();
+        opts
+    }
+
+    fn parsed(text: &str) -> Map<String, Value> {
+        match toml::from_str(text).unwrap() {
+            Value::Object(map) => map,
+            _ => panic!("not a table"),
+        }
+    }
+
+    fn merged(argv: &[&str], file: &Map<String, Value>, env: &[(&str, &str)]) -> Vec<String> {
+        let argv = std::iter::once("NetDelay").chain(argv.iter().copied()).map(OsString::from).collect();
+        let env = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        merge(argv, file, env).unwrap().into_iter().map(|a| a.into_string().unwrap()).collect()
+    }
+
+    fn client(argv: &[&str], file: &Map<String, Value>, env: &[(&str, &str)]) -> ClientOpts {
+        match Cli::from_iter_safe(merged(argv, file, env)).unwrap().cmd {
+            Cmd::Client(opts) => opts,
+            cmd => panic!("not a client: {:?}", cmd),
+        }
+    }
+
+    /// write `text` to a config file named for the test and load it
+    fn load_text(name: &str, text: &str) -> Result<Map<String, Value>> {
+        let path = std::env::temp_dir().join(format!("netdelay-config-{}-{}.toml", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        let map = load(&path);
+        std::fs::remove_file(&path).unwrap();
+        map
+    }
+
+    #[test]
+    fn tables_match_the_cli() {
+        assert_eq!(help_options(ServerOpts::clap()), sorted(SERVER));
+        assert_eq!(help_options(ClientOpts::clap()), sorted(CLIENT));
+        assert_eq!(help_options(AnalyzeOpts::clap()), sorted(ANALYZE));
+        assert_eq!(help_options(SelftestOpts::clap()), sorted(SELFTEST));
+    }
+
+    #[test]
+    fn command_line_beats_environment_beats_file() {
+        let file = parsed("[client]\nport = 1\ncount = 7\ntargets = [\"10.0.0.1\"]\n");
+        let opts = client(&["client"], &file, &[]);
+        assert_eq!((opts.port, opts.count), (1, Some(7)));
+        let opts = client(&["client"], &file, &[("port", "2")]);
+        assert_eq!((opts.port, opts.count), (2, Some(7)));
+        let opts = client(&["client", "-p", "3"], &file, &[("port", "2")]);
+        assert_eq!((opts.port, opts.count), (3, Some(7)));
+        let opts = client(&["client", "--port=4", "-n5"], &file, &[("port", "2")]);
+        assert_eq!((opts.port, opts.count), (4, Some(5)));
+    }
+
+    #[test]
+    fn command_comes_from_the_file_when_not_given() {
+        let file = parsed("command = \"client\"\n[client]\ntargets = [\"10.0.0.1\"]\n");
+        assert_eq!(client(&[], &file, &[]).targets[0].host, "10.0.0.1");
+    }
+
+    #[test]
+    fn flag_from_file_can_be_turned_off() {
+        let file = parsed("[client]\nudp = true\nhuman_time = true\ntargets = [\"10.0.0.1\"]\n");
+        let opts = client(&["client"], &file, &[]);
+        assert!(opts.udp && opts.human_time);
+        let opts = client(&["client", "--no-udp"], &file, &[]);
+        assert!(!opts.udp && opts.human_time);
+        let opts = client(&["client"], &file, &[("udp", "false")]);
+        assert!(!opts.udp);
+        // without a config file too
+        assert!(!client(&["client", "--no-udp", "10.0.0.1"], &Map::new(), &[]).udp);
+    }
+
+    #[test]
+    fn target_tables_become_targets() {
+        let file = parsed("[client]\n[[client.targets]]\nname = \"dc1\"\naddr = \"10.0.0.1\"\nwarn_threshold = \"500ms\"\n\
+                         [[client.targets]]\naddr = \"10.0.1.1:5151\"\n");
+        let opts = client(&["client"], &file, &[]);
+        assert_eq!(opts.targets.len(), 2);
+        assert_eq!((opts.targets[0].name.as_deref(), opts.targets[0].host.as_str()), (Some("dc1"), "10.0.0.1"));
+        assert_eq!(opts.targets[0].warn_threshold, Some(std::time::Duration::from_millis(500)));
+        assert_eq!((opts.targets[1].host.as_str(), opts.targets[1].port), ("10.0.1.1", Some(5151)));
+        // targets on the command line replace those in the file
+        let opts = client(&["client", "-p", "1", "10.0.2.1"], &file, &[]);
+        assert_eq!(opts.targets.len(), 1);
+        assert_eq!(opts.targets[0].host, "10.0.2.1");
+        assert!(target_spec(&parsed("port = 1\n")).is_err());
+    }
+
+    #[test]
+    fn only_log_options_on_the_command_line_replace_the_file_ones() {
+        let file = parsed("log_level = \"warn\"\n[client]\ntargets = [\"10.0.0.1\"]\n");
+        assert!(merged(&["client"], &file, &[]).contains(&"--log-level=warn".to_string()));
+        // a value that happens to start with -l is not a log option
+        assert!(merged(&["client", "--client-name", "-lab"], &file, &[]).contains(&"--log-level=warn".to_string()));
+        assert!(merged(&["client", "--record=-lab"], &file, &[]).contains(&"--log-level=warn".to_string()));
+        for given in [&["-l", "debug", "client"][..], &["client", "-ldebug"], &["--log-level=debug", "client"], &["client", "-L", "log.yaml"]] {
+            assert!(!merged(given, &file, &[]).contains(&"--log-level=warn".to_string()), "{:?}", given);
+        }
+    }
+
+    #[test]
+    fn unknown_keys_are_errors() {
+        assert!(load_text("good", "log_level = \"info\"\ncommand = \"client\"\n[client]\nport = 1\ntarget = \"x\"\n[server]\nbinds = []\n").is_ok());
+        let e = load_text("top", "bogus = 1\n").unwrap_err();
+        assert!(format!("{:#}", e).contains("unknown option \"bogus\""), "{:#}", e);
+        let e = load_text("section", "[client]\nbind = \"x\"\n").unwrap_err();
+        assert!(format!("{:#}", e).contains("unknown client option \"bind\""), "{:#}", e);
+        let e = load_text("table", "[nosuch]\nport = 1\n").unwrap_err();
+        assert!(format!("{:#}", e).contains("unknown option \"nosuch\""), "{:#}", e);
+        // the environment is shared so variables that are no option are ignored
+        assert_eq!(client(&["client", "10.0.0.1"], &Map::new(), &[("bogus", "1")]).port, 5150);
+    }
+}
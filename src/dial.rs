@@ -0,0 +1,196 @@
+use std::fmt;
+use std::net::{Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Context};
+use log::debug;
+
+use crate::{shutdown, SHUTDOWN_POLL};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// how long a connection attempt gets before the next address is tried alongside it - RFC 8305
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// a host name or ip and port kept as given so it can be looked up again on every connect
+#[derive(Debug, Clone)]
+pub struct HostPort {
+    pub host: String,
+    pub port: u16,
+}
+
+impl fmt::Display for HostPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// split `host`, `host:port`, `ip6` or `[ip6]:port` - the port is None when not given
+pub fn host_port_from_str(s: &str) -> Result<(String, Option<u16>)> {
+    let port = |p: &str| p.parse::<u16>().with_context(|| format!("port \"{}\" of \"{}\" is not a number up to 65535", p, s));
+    if let Some(rest) = s.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(|| anyhow!("\"{}\" is missing the ] after the ipv6 address", s))?;
+        return match rest.strip_prefix(':') {
+            Some(p) => Ok((host.to_string(), Some(port(p)?))),
+            None if rest.is_empty() => Ok((host.to_string(), None)),
+            None => Err(anyhow!("\"{}\" has \"{}\" after the ipv6 address instead of :port", s, rest)),
+        };
+    }
+    if s.parse::<Ipv6Addr>().is_ok() {
+        return Ok((s.to_string(), None));
+    }
+    match s.rsplit_once(':') {
+        Some((host, p)) if !host.is_empty() => Ok((host.to_string(), Some(port(p)?))),
+        Some(_) => Err(anyhow!("\"{}\" has no host before the port", s)),
+        None if s.is_empty() => Err(anyhow!("empty host")),
+        None => Ok((s.to_string(), None)),
+    }
+}
+
+/// a failed host lookup - kept as a type so it can be told apart from a failed connect
+#[derive(Debug)]
+pub struct LookupFailed(String);
+
+impl fmt::Display for LookupFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for LookupFailed {}
+
+impl HostPort {
+    /// look the host up now - addresses alternate between families starting with the resolver's first choice
+    pub fn resolve(&self) -> Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = (self.host.as_str(), self.port).to_socket_addrs()
+            .with_context(|| LookupFailed(format!("Unable to get socket address from {}", self)))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(LookupFailed(format!("empty result from DNS lookup for: {}", self)).into());
+        }
+        let ordered = interleave(addrs);
+        debug!("{} resolved to {:?}", self, ordered);
+        Ok(ordered)
+    }
+}
+
+/// `addrs` alternating between families, starting with that of the first, each family kept in order - RFC 8305
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(|a| a.is_ipv6());
+    let (mut first, mut other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut ordered = Vec::with_capacity(first.len() + other.len());
+    first.reverse();
+    other.reverse();
+    while !first.is_empty() || !other.is_empty() {
+        ordered.extend(first.pop());
+        ordered.extend(other.pop());
+    }
+    ordered
+}
+
+/// connect to whichever of `addrs` answers first, happy eyeballs style
+///
+/// each attempt gets ATTEMPT_DELAY before the next address is tried alongside it, or less
+/// if it fails sooner.  Connections that lose the race are closed
+pub fn connect_tcp(addrs: &[SocketAddr], timeout: Duration) -> Result<(TcpStream, SocketAddr)> {
+    let (tx, rx) = mpsc::channel();
+    let mut errors: Vec<String> = vec![];
+    // the first error is kept whole so what went wrong can be told
+    let mut first: Option<std::io::Error> = None;
+    let mut running = 0;
+    let mut next = addrs.iter();
+    loop {
+        let mut wait = ATTEMPT_DELAY;
+        if let Some(addr) = next.next() {
+            let (tx, addr) = (tx.clone(), *addr);
+            debug!("connecting to {}", addr);
+            std::thread::Builder::new()
+                .name(format!("connect_{}", addr))
+                .spawn(move || {
+                    let _ = tx.send((addr, TcpStream::connect_timeout(&addr, timeout)));
+                })
+                .context("spawning connect thread")?;
+            running += 1;
+        } else if running == 0 {
+            let msg = format!("unable to connect to any of {}", errors.join(", "));
+            return Err(match first {
+                Some(e) => anyhow::Error::from(e).context(msg),
+                None => anyhow!(msg),
+            });
+        } else {
+            // every address is being tried - wait for the last of them
+            wait = timeout + ATTEMPT_DELAY;
+        }
+        // wait in slices so a shutdown is not held up by a connect timing out
+        let until = Instant::now() + wait;
+        let res = loop {
+            if shutdown::requested() {
+                return Err(anyhow!("shutting down while connecting"));
+            }
+            let remaining = until.saturating_duration_since(Instant::now());
+            match rx.recv_timeout(remaining.min(SHUTDOWN_POLL)) {
+                Err(RecvTimeoutError::Timeout) if remaining > SHUTDOWN_POLL => {},
+                res => break res,
+            }
+        };
+        match res {
+            Ok((addr, Ok(stream))) => return Ok((stream, addr)),
+            Ok((addr, Err(e))) => {
+                running -= 1;
+                errors.push(format!("{} ({})", addr, e));
+                first.get_or_insert(e);
+            },
+            Err(_) => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_port_forms() {
+        let ok: &[(&str, &str, Option<u16>)] = &[
+            ("host", "host", None),
+            ("host:5151", "host", Some(5151)),
+            ("10.0.0.1:80", "10.0.0.1", Some(80)),
+            ("::1", "::1", None),
+            ("fe80::1:2", "fe80::1:2", None),
+            ("[::1]", "::1", None),
+            ("[::1]:80", "::1", Some(80)),
+        ];
+        for (s, host, port) in ok {
+            assert_eq!(host_port_from_str(s).unwrap(), (host.to_string(), *port), "{}", s);
+        }
+    }
+
+    #[test]
+    fn host_port_errors() {
+        for s in ["", "[::1]x", "[::1", "[::1]:", "[::1]:x", "host:", ":80", "h:99999", "h:-1"] {
+            assert!(host_port_from_str(s).is_err(), "{} parsed", s);
+        }
+    }
+
+    #[test]
+    fn families_alternate_starting_with_the_first() {
+        let addrs = |list: &[&str]| -> Vec<SocketAddr> { list.iter().map(|a| a.parse().unwrap()).collect() };
+        let (a4, b4, c4) = ("10.0.0.1:1", "10.0.0.2:1", "10.0.0.3:1");
+        let (a6, b6) = ("[2001:db8::1]:1", "[2001:db8::2]:1");
+        assert_eq!(interleave(addrs(&[a6, b6, a4, b4, c4])), addrs(&[a6, a4, b6, b4, c4]));
+        assert_eq!(interleave(addrs(&[a4, b4, c4, a6])), addrs(&[a4, a6, b4, c4]));
+        assert_eq!(interleave(addrs(&[b4, a4])), addrs(&[b4, a4]));
+        assert!(interleave(vec![]).is_empty());
+    }
+
+    #[test]
+    fn resolve_ip_literal() {
+        let addrs = HostPort { host: "::1".to_string(), port: 80 }.resolve().unwrap();
+        assert_eq!(addrs, vec!["[::1]:80".parse::<SocketAddr>().unwrap()]);
+        assert!(HostPort { host: "no-such-host.invalid".to_string(), port: 80 }.resolve().unwrap_err().downcast_ref::<LookupFailed>().is_some());
+    }
+}
@@ -0,0 +1,62 @@
+/// RFC 3550 interarrival jitter and RFC 3393 delay variation over consecutive echos
+///
+/// both work on differences between the delay of one echo and the one before it,
+/// so the round trip time works as well as a one-way transit time here
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Jitter {
+    last_nanos: Option<i64>,
+    /// RFC 3550 smoothed jitter in nanoseconds - J += (|D| - J) / 16
+    jitter: f64,
+}
+
+impl Jitter {
+    /// feed the delay of the next echo - returns the IPDV against the previous one if there was one
+    pub fn add(&mut self, delay_nanos: i64) -> Option<i64> {
+        let ipdv = self.last_nanos.map(|last| delay_nanos - last);
+        if let Some(d) = ipdv {
+            self.jitter += ((d.abs() as f64) - self.jitter) / 16.0;
+        }
+        self.last_nanos = Some(delay_nanos);
+        ipdv
+    }
+
+    /// IPDV is only defined between consecutive echos so a lost one breaks the chain
+    pub fn gap(&mut self) {
+        self.last_nanos = None;
+    }
+
+    pub fn jitter_nanos(&self) -> i64 {
+        self.jitter as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_moves_a_sixteenth_of_the_way_to_each_difference() {
+        let mut j = Jitter::default();
+        assert_eq!(j.add(1000), None);
+        assert_eq!(j.jitter_nanos(), 0);
+        assert_eq!(j.add(2600), Some(1600));
+        assert_eq!(j.jitter_nanos(), 100);
+        // a drop counts the same as a rise
+        assert_eq!(j.add(1000), Some(-1600));
+        assert_eq!(j.jitter_nanos(), 193);
+        assert_eq!(j.add(1000), Some(0));
+        assert_eq!(j.jitter_nanos(), 181);
+    }
+
+    #[test]
+    fn gap_breaks_the_ipdv_chain_but_keeps_the_jitter() {
+        let mut j = Jitter::default();
+        j.add(1000);
+        j.add(2600);
+        j.gap();
+        assert_eq!(j.add(50_000), None);
+        assert_eq!(j.jitter_nanos(), 100);
+        assert_eq!(j.add(50_160), Some(160));
+        assert_eq!(j.jitter_nanos(), 103);
+    }
+}
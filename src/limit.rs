@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+
+use crate::{util, shutdown};
+
+/// where a client run stops - after a number of echos sent or connects that failed, at a
+/// deadline, on a termination signal, or never
+///
+/// shared by every connection the client makes so reconnects do not reset it
+pub struct Limit {
+    remaining: Option<u64>,
+    deadline: Option<Instant>,
+}
+
+impl Limit {
+    pub fn new(count: Option<u64>, duration: Option<Duration>) -> Self {
+        Limit {
+            remaining: count,
+            deadline: duration.map(|d| Instant::now() + d),
+        }
+    }
+
+    pub fn unbounded() -> Self {
+        Limit::new(None, None)
+    }
+
+    pub fn done(&self) -> bool {
+        shutdown::requested() || self.remaining == Some(0) || self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    /// whether the deadline has passed - unlike `done` not true for a count used up
+    pub fn expired(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    /// `dur` cut to what is left until the deadline, so a connect or read waiting on a silent
+    /// target can not carry the run past it
+    pub fn cap(&self, dur: Duration) -> Duration {
+        match self.deadline {
+            Some(deadline) => dur.min(deadline.saturating_duration_since(Instant::now())),
+            None => dur,
+        }
+    }
+
+    /// take one echo off the count - false once the run is over and nothing should be sent
+    pub fn take(&mut self) -> bool {
+        if self.done() {
+            return false;
+        }
+        if let Some(ref mut remaining) = self.remaining {
+            *remaining -= 1;
+        }
+        true
+    }
+
+    /// undo a `take` that turned out not to be used up
+    pub fn give_back(&mut self) {
+        if let Some(ref mut remaining) = self.remaining {
+            *remaining += 1;
+        }
+    }
+
+    /// sleep for `dur` but never past the deadline or a shutdown request
+    pub fn sleep(&self, dur: Duration) {
+        let mut dur = dur;
+        if let Some(deadline) = self.deadline {
+            dur = dur.min(deadline.saturating_duration_since(Instant::now()));
+        }
+        shutdown::wait(dur);
+    }
+
+    /// like `util::sleep_until_even_interval` but never past the deadline or a shutdown request
+    pub fn sleep_until_even_interval(&self, interval: &Duration) {
+        let mut until_next = util::compute_until_even_interval_nanos(None, interval);
+        if let Some(deadline) = self.deadline {
+            until_next = until_next.min(deadline.saturating_duration_since(Instant::now()));
+        }
+        shutdown::wait(until_next);
+    }
+}
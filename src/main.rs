@@ -4,8 +4,35 @@
 #![allow(unused_mut)]
 #![allow(unreachable_code)]
 
+#[macro_use]
 mod util;
 mod cli;
+mod udp;
+mod seq;
+mod clock;
+mod stat;
+mod jitter;
+mod sweep;
+mod limit;
+mod summary;
+mod sla;
+mod shutdown;
+mod peers;
+mod sink;
+mod session;
+mod tick;
+mod metrics;
+mod push;
+mod otlp;
+mod record;
+mod analyze;
+mod server;
+mod selftest;
+mod config;
+mod target;
+mod dial;
+mod backoff;
+mod outage;
 
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -14,73 +41,37 @@ use anyhow::{anyhow, Context};
 use log::{debug, error, info, trace, warn};
 use log::LevelFilter;
 use std::net::{TcpListener, TcpStream, SocketAddr, IpAddr, Ipv4Addr};
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
 use std::sync::{Arc, Mutex, Condvar};
 use std::sync::atomic::{AtomicU64, AtomicBool, Ordering};
 use util::{to_log_level, to_duration, to_size_usize};
 use std::str::FromStr;
-use std::thread::spawn;
+use std::thread::{spawn, JoinHandle};
 use core::mem;
 use humantime::parse_duration;
 use lazy_static::lazy_static;
-use crate::cli::Cli;
+use crate::cli::{Cli, Cmd, ClientOpts};
+use crate::dial::HostPort;
+use crate::backoff::Backoff;
+use crate::server::EchoServer;
 use serde::{Serialize, Deserialize, Serializer};
+use bincode::Options;
 use std::sync::mpsc::RecvTimeoutError::Timeout;
 use std::ops::Deref;
 use std::borrow::BorrowMut;
 use std::fmt::Formatter;
+use crate::seq::{SeqTracker, Arrival};
+use crate::clock::ClockOffset;
+use crate::stat::{Stat, StatSnapshot, SignedDelay};
+use crate::limit::Limit;
+use crate::peers::Peers;
+use crate::session::Session;
+use crate::sink::Sink;
+use crate::metrics::Metrics;
+use crate::record::{Recorder, TargetRecorder};
+use chrono::Utc;
 
 
-struct _Stat {
-    echos: u64,
-    tot_time: Duration,
-    max_time: Duration,
-    min_time: Duration,
-}
-
-#[derive(Clone)]
-struct Stat {
-    inner: Arc<Mutex<_Stat>>,
-}
-
-impl _Stat {
-    fn zero(self: &mut Self) {
-        self.max_time =Duration::from_secs(0);
-        self.min_time =Duration::from_secs(u64::MAX);
-        self.tot_time =Duration::from_secs(0);
-        self.echos=0;
-    }
-}
-
-impl Stat {
-    pub fn new() -> Self {
-        Stat {
-            inner: Arc::new(Mutex::new(_Stat {
-                echos: 0,
-                tot_time: Duration::from_secs(0),
-                max_time: Duration::from_secs(0),
-                min_time: Duration::from_millis(u64::MAX),
-            }))
-        }
-    }
-
-    pub fn update(self: &mut Self, time_ms: Duration) {
-        let mut lock = self.inner.lock().expect("Unable to update State at lock");
-        lock.echos += 1;
-        lock.tot_time += time_ms;
-        lock.max_time = lock.max_time.max(time_ms);
-        lock.min_time = lock.min_time.min(time_ms);
-
-    }
-    pub fn snap_shot(self: &mut Self) -> (u64, Duration, Duration, Duration) {
-        let mut lock = self.inner.lock().expect("Unable to take snap_shot of Stat at lock");
-        let (echos, tot_time, max_time, min_time) =
-            (lock.echos, lock.tot_time, lock.max_time, lock.min_time);
-        lock.zero();
-        (echos, tot_time, max_time, min_time)
-    }
-}
-
 lazy_static! {
     pub static ref STOP_TICKER: AtomicBool = AtomicBool::new(false);
 
@@ -89,19 +80,43 @@ lazy_static! {
 
 type Result<T> = anyhow::Result<T, anyhow::Error>;
 
+/// wire format of an echo - all times are wall-clock nanoseconds since the UNIX epoch
+///
+/// the two server times are only comparable to the client's when both clocks are synchronised
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 struct TimePacket {
-    #[serde(with = "serde_millis")]
-    send_time: std::time::Instant,
-    #[serde(with = "serde_millis")]
-    resp_time: Option<std::time::Instant>,
+    /// per-session sequence number set by the client and echoed back as is
+    seq: u64,
+    client_send: u64,
+    server_recv: Option<u64>,
+    server_send: Option<u64>,
+    client_recv: Option<u64>,
+    /// filler so echos can be sized with --payload-size - echoed back as is
+    #[serde(with = "serde_bytes")]
+    pad: Vec<u8>,
+}
+
+/// bytes an echo takes besides its pad, with room to spare
+const ECHO_HEADER: u64 = 64;
+
+/// the wire encoding of echos - that of `bincode::serialize` but failing on an echo larger than
+/// the largest --payload-size allows, so a bad length prefix can not make a reader allocate without bound
+pub fn echo_codec() -> impl bincode::Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(cli::MAX_PAYLOAD_SIZE as u64 + ECHO_HEADER)
 }
 
 impl TimePacket {
-    pub fn new() -> Self {
+    pub fn new(seq: u64, payload_size: usize) -> Self {
         TimePacket {
-            send_time: std::time::Instant::now(),
-            resp_time: None,
+            seq,
+            client_send: util::unix_nanos(),
+            server_recv: None,
+            server_send: None,
+            client_recv: None,
+            pad: vec![0u8; payload_size],
         }
     }
 }
@@ -109,121 +124,220 @@ impl TimePacket {
 
 fn main() {
 
-    if let Err(err) = run() {
-        eprintln!("Error: {:?}", &err);
-        error!("Error: {:?}", &err);
-        std::process::exit(1);
+    match run() {
+        Err(err) => {
+            eprintln!("Error: {:?}", &err);
+            error!("Error: {:?}", &err);
+            std::process::exit(sla::EXIT_ERROR);
+        },
+        Ok(code) => std::process::exit(code),
     }
 }
 
-fn run() -> Result<()> {
-    let cli: Cli = Cli::from_args();
+/// returns the process exit code - see `sla` for what they mean
+fn run() -> Result<i32> {
+    let cli: Cli = Cli::from_iter(config::args()?);
 
     util::init_log(&cli).context("initializing log configuration")?;
+    shutdown::install().context("installing termination signal handler")?;
+
+    match cli.cmd {
+        Cmd::Server(ref opts) => {
+            let server = EchoServer::start(opts)?;
+            while !shutdown::wait(Duration::from_secs(3600)) {}
+            server.stop()?;
+            Ok(sla::EXIT_OK)
+        },
+        Cmd::Client(ref opts) => run_client(opts),
+        Cmd::Analyze(ref opts) => {
+            analyze::run(opts)?;
+            Ok(sla::EXIT_OK)
+        },
+        Cmd::Selftest(ref opts) => selftest::run(opts),
+    }
+}
 
-    if let Some(ref socket_addr) = cli.server {
-        let mut socket_addr = if let Some(ref socket_addr) = socket_addr {
-            util::str_to_socketaddr(socket_addr)?
-        } else {
-            SocketAddr::new(IpAddr::from(Ipv4Addr::new(0, 0, 0, 0)), cli.port)
-        };
-        socket_addr.set_port(cli.port);
-
-        info!("server listening to {}", &socket_addr);
-        let listener = TcpListener::bind(socket_addr).with_context(|| format!("not a valid IP address: {}", &socket_addr))?;
-        // accept connections and process them serially
-        let mut serv_count = 0;
-        for stream in listener.incoming() {
-            let stream = stream?;
-            serv_count += 1;
-            let cli = cli.clone();
-            std::thread::Builder::new()
-                .name(format!("serv_{}", serv_count))
-                .spawn(move || {
-                    server_thread_handler(stream, &cli);
-                }).unwrap();
+/// one --target with its own options, address and stats
+struct ClientTarget {
+    name: String,
+    cli: ClientOpts,
+    addr: HostPort,
+    stat: Stat,
+}
+
+/// echo to every target until the run limits or a signal stop the client then print a summary
+/// per target - returns the SLA exit codes of all targets OR'd together
+pub fn run_client(cli: &ClientOpts) -> Result<i32> {
+    if cli.udp && cli.sweep.is_none() {
+        udp::check_payload_size(cli.payload_size)?;
+    }
+    let mut targets: Vec<ClientTarget> = vec![];
+    for spec in cli.targets.iter() {
+        let addr = spec.host_port(cli.port);
+        let name = spec.name.clone().unwrap_or_else(|| addr.to_string());
+        if targets.iter().any(|t| t.name == name) {
+            return Err(anyhow!("target {} given more than once - name them apart with name=ip:port", name));
         }
-    } else if let Some(mut socker_addr) = cli.client {
         let mut stat = Stat::new();
-        if let Some(ticker_interval) = cli.ticker_interval {
-            let cli = cli.clone();
-            spawn_ticker(&cli, ticker_interval, stat.clone());
+        if cli.clock_offset {
+            stat = stat.with_clock_offset(cli.offset_window);
         }
-        socker_addr.set_port(cli.port);
-        client_forever(&cli, stat, &socker_addr);
-        stop_ticker();
-
-    } else {
-        return Err(anyhow!("Error - either server or client must be specified"))?;
+        targets.push(ClientTarget { name, cli: spec.apply(cli), addr, stat });
     }
-
-    Ok(())
-}
-
-fn server_thread_handler(mut stream: TcpStream, cli: &Cli) {
-    match server(stream, &cli) {
-        Err(e) => warn!("client thread error: {}", single_line_error(&e)),
-        Ok(()) => {}
+    if let Some(sizes) = cli.sweep {
+        // a sweep keeps a Stat per payload size so none of these would see its echos
+        let unused = [("--ticker-interval", cli.ticker_interval.is_some()), ("--tick-out", cli.tick_out.is_some())
+            , ("--outage-out", cli.outage_out.is_some()), ("--metrics-addr", cli.metrics_addr.is_some())
+            , ("--push-addr", cli.push_addr.is_some()), ("--otlp-endpoint", cli.otlp_endpoint.is_some())
+            , ("--record", cli.record.is_some())];
+        if let Some((opt, _)) = unused.iter().find(|(_, set)| *set) {
+            return Err(anyhow!("{} cannot be used with --sweep - a sweep only prints its latency by size table and writes --echo-out", opt));
+        }
+        let echo_out = cli.echo_out.as_ref().map(|p| Sink::open(p, cli.echo_format)).transpose()?;
+        for t in targets.iter() {
+            sweep::run(&t.cli, &t.name, &t.addr, sizes, echo_out.clone())?;
+        }
+        return Ok(sla::EXIT_OK);
+    }
+    if let Some(ref metrics_addr) = cli.metrics_addr {
+        let metrics = Metrics::new();
+        targets.iter().for_each(|t| metrics.add(&t.name, t.stat.clone()));
+        metrics.serve(metrics_addr)?;
+    }
+    let names: Vec<String> = targets.iter().map(|t| t.name.clone()).collect();
+    let outputs = tick::Outputs::open(cli, &names)?;
+    let ticker = cli.ticker_interval.map(|ticker_interval| spawn_ticker(cli, ticker_interval
+        , targets.iter().map(|t| (t.name.clone(), t.stat.clone())).collect(), outputs));
+    let echo_out = cli.echo_out.as_ref().map(|p| Sink::open(p, cli.echo_format)).transpose()?;
+    let outage_out = cli.outage_out.as_ref().map(|p| Sink::open(p, cli.outage_format)).transpose()?;
+    let recorder = cli.record.as_ref().map(|p| Recorder::open(p)).transpose()?;
+    let started = Instant::now();
+    let mut threads = vec![];
+    for t in targets.iter() {
+        let (cli, stat, addr) = (t.cli.clone(), t.stat.clone(), t.addr.clone());
+        let record = recorder.as_ref().map(|r| r.for_target(&t.name));
+        let outputs = Session::new(&t.name, 0, echo_out.clone(), outage_out.clone(), record);
+        let thread = std::thread::Builder::new()
+            .name(format!("client_{}", t.name))
+            .spawn(move || {
+                let mut limit = Limit::new(cli.count, cli.duration);
+                client_forever(&cli, stat, &addr, &mut limit, outputs);
+            })
+            .context("spawning client thread")?;
+        threads.push(thread);
     }
+    for thread in threads {
+        thread.join().map_err(|_| anyhow!("client thread panicked"))?;
+    }
+    stop_ticker();
+    if let Some(ticker) = ticker {
+        ticker.join().map_err(|_| anyhow!("ticker thread panicked"))?;
+    }
+    let mut code = sla::EXIT_OK;
+    for t in targets.iter() {
+        if let Some(outage) = t.stat.ongoing_outage() {
+            outage::report(&t.name, &outage, outage_out.as_ref());
+        }
+        let summary = t.stat.summary();
+        summary::print(cli.human_time, &t.name, &summary, started.elapsed());
+        code |= sla::check(&t.cli, &t.name, &summary);
+    }
+    Ok(code)
 }
 
-fn single_line_error(e: &anyhow::Error) -> String {
+/// how often blocking socket reads wake up to check for a shutdown request
+pub const SHUTDOWN_POLL: Duration = Duration::from_millis(250);
+
+pub fn single_line_error(e: &anyhow::Error) -> String {
     let mut s = format!("{:?}", e);
     s = s.replace("\n", " ");
     s = s.replace("    ", " ");
     s
 }
 
-fn server(mut stream: TcpStream, cli: &Cli) -> Result<()> {
-    stream.set_read_timeout(Some(cli.timeout_socket)).context("setting read timeout")?;
-    stream.set_write_timeout(Some(cli.timeout_socket)).context("setting write timeout")?;
-    stream.set_nodelay(true).context("setting nodelay of server socket");
-    let client_addr = stream.peer_addr().context("Unable to get peer_address after incoming connection")?;
-    info!("Connection from: {:?}", &client_addr);
 
-    loop {
-        let mut tp: TimePacket = bincode::deserialize_from(&stream).context(format!("with client IP {} at read", client_addr))?;
-        tp.resp_time = Some(std::time::Instant::now());
-        bincode::serialize_into(&stream, &tp).context(format!("with client IP {} at write", client_addr))?;
-        stream.flush();
-        if let Some(ref dur) = cli.interval {
-            std::thread::sleep(*dur);
-        }
-        debug!("Packet sent {:#?}", &tp);
-    }
-    return Ok(());
-}
-
-
-fn build_client_stream(cli: &Cli, socker_addr: &SocketAddr) -> Result<TcpStream> {
-    let mut stream = TcpStream::connect_timeout(&socker_addr, cli.timeout_socket).context("setting connect timeout of client socket")?;
+/// connect to whichever of `addrs` answers first within `timeout` and return the address it was
+fn build_client_stream(cli: &ClientOpts, addrs: &[SocketAddr], timeout: Duration) -> Result<(TcpStream, SocketAddr)> {
+    let (mut stream, socker_addr) = dial::connect_tcp(addrs, timeout)?;
     stream.set_read_timeout(Some(cli.timeout_socket)).context("setting read timeout of client socket")?;
     stream.set_write_timeout(Some(cli.timeout_socket)).context("setting write timeout of client socket")?;
-    stream.set_nodelay(true).context("setting nodelay").context("setting nodelay of client socket");
+    stream.set_nodelay(true).context("setting nodelay of client socket")?;
+
+    Ok((stream, socker_addr))
+}
 
-    Ok(stream)
+/// make a connection to one of the addresses a target resolved to, taking no longer than the timeout, and say which
+type Build<T> = fn(&ClientOpts, &[SocketAddr], Duration) -> Result<(T, SocketAddr)>;
+
+/// keep looking `dest` up and trying to connect to it until that works - None if the run limit is reached first
+fn connect_with_retry<T>(cli: &ClientOpts, stat: &mut Stat, limit: &mut Limit, backoff: &mut Backoff, session: &Session, dest: &HostPort, build: Build<T>) -> Option<T> {
+    info!("client trying to connect to {}", dest);
+    let (conn, socker_addr) = loop {
+        // like ping -c a connect that fails uses up one of --count so a dead target does not hang the run
+        if !limit.take() {
+            return None;
+        }
+        // a target that did not answer before the end of the run still failed to - a zero timeout is refused
+        let timeout = limit.cap(cli.timeout_socket).max(Duration::from_millis(1));
+        match dest.resolve().and_then(|addrs| build(cli, &addrs, timeout)) {
+            Err(_) if shutdown::requested() => return None,
+            Err(e) => {
+                stat.conn_failure(outage::classify(&e));
+                session.conn_failure(&single_line_error(&e));
+                error!("Unable to build client stream: {}", single_line_error(&e));
+                if limit.done() {
+                    return None;
+                }
+                let (attempt, delay) = backoff.failed();
+                info!("Will attempt to connect to {} again after a break of {} - attempt {} in a row failed", dest, duration_to_human(&delay, 2), attempt);
+                limit.sleep(delay);
+            },
+            Ok(s) => {
+                limit.give_back();
+                break s;
+            },
+        }
+    };
+    stat.connected();
+    session.connected();
+    backoff.connected();
+    info!("client connected to {} at {}", dest, socker_addr);
+    Some(conn)
 }
 
-fn client_forever(cli: &Cli, mut stat: Stat, socker_addr: &SocketAddr) {
+/// echo, reconnecting whenever that fails, until `limit` says the run is over
+///
+/// every connection gets a copy of `outputs` with its own conn_id
+fn client_forever(cli: &ClientOpts, mut stat: Stat, dest: &HostPort, limit: &mut Limit, outputs: Session) {
+    let target = outputs.target.as_str();
+    let mut conn_id = 0;
+    let mut backoff = Backoff::new(cli);
     loop {
-        info!("client trying to connect to {}", &socker_addr);
-        let mut stream = loop {
-            match build_client_stream(&cli, &socker_addr) {
-                Err(e) => {
-                    error!("Unable to build client stream: {}", single_line_error(&e));
-                    info!("Will attempt to reconnect after a short break of {} seconds", cli.break_time.as_secs());
-                    util::sleep_until_even_interval(None, &cli.break_time);
-                },
-                Ok(s) => break s,
+        conn_id += 1;
+        let session = Session { conn_id, ..outputs.clone() };
+        let res = if cli.udp {
+            match connect_with_retry(cli, &mut stat, limit, &mut backoff, &session, dest, udp::build_client_socket) {
+                Some(socket) => udp::client(socket, cli, dest, stat.clone(), cli.payload_size, limit, &session),
+                None => break,
+            }
+        } else {
+            match connect_with_retry(cli, &mut stat, limit, &mut backoff, &session, dest, build_client_stream) {
+                Some(stream) => client(stream, cli, stat.clone(), cli.payload_size, limit, &session),
+                None => break,
             }
         };
-        info!("client connected to {}", &socker_addr);
 
-        match client(stream, &cli, stat.clone()) {
+        match res {
             Err(e) => {
-                error!("Error after connection: {}", single_line_error(&e));
-                info!("Will attempt to reconnect after a short break of {} seconds", cli.break_time.as_secs());
-                util::sleep_until_even_interval(None, &cli.break_time);
+                stat.conn_failure(outage::classify(&e));
+                session.conn_failure(&single_line_error(&e));
+                error!("Error after connection to {}: {}", target, single_line_error(&e));
+                if limit.done() {
+                    break;
+                }
+                let (attempt, delay) = backoff.failed();
+                info!("Will attempt to reconnect to {} after a break of {} - attempt {} in a row failed", dest, duration_to_human(&delay, 2), attempt);
+                limit.sleep(delay);
             },
             Ok(()) => break,
         }
@@ -231,42 +345,153 @@ fn client_forever(cli: &Cli, mut stat: Stat, socker_addr: &SocketAddr) {
     }
 }
 
-fn client(mut stream: TcpStream, cli: &Cli, mut stat: Stat) -> Result<()> {
+/// echo `payload_size` padded packets over a connected stream until `limit` says the run is over
+fn client(mut stream: TcpStream, cli: &ClientOpts, mut stat: Stat, payload_size: usize, limit: &mut Limit, session: &Session) -> Result<()> {
+    let mut seqs = SeqTracker::new();
+    let mut buf = vec![];
+    let mut lost_in_row = 0;
     loop {
         let server_addr = stream.peer_addr().context("Unable to get peer_address after incoming connection")?;
 
-        let tp_sent = TimePacket::new();
-        bincode::serialize_into(&stream, &tp_sent).context(format!("with IP server {} at read", server_addr))?;
-        let tp_recv: TimePacket = bincode::deserialize_from(&stream).context(format!("with IP server {} at write", server_addr))?;
-        let dur = tp_recv.send_time.elapsed();
+        if !limit.take() {
+            return Ok(());
+        }
+        let tp_sent = TimePacket::new(seqs.next(), payload_size);
+        let out = echo_codec().serialize(&tp_sent).context("serializing echo")?;
+        let sent_at = Instant::now();
+        stream.write_all(&out).context(format!("with IP server {} at write", server_addr))?;
+        stat.sent();
+
+        // keep reading until our own echo comes back - anything older is late
+        let deadline = sent_at + limit.cap(cli.timeout_socket);
+        let reply = loop {
+            let tp_recv = match read_echo(&mut stream, &mut buf, deadline).with_context(|| format!("with IP server {} at read", server_addr)) {
+                Ok(Some(tp_recv)) => tp_recv,
+                Ok(None) if shutdown::requested() || limit.expired() => return Ok(()),
+                Ok(None) => break None,
+                Err(e) => return Err(in_flight_lost(&mut stat, session, tp_sent.seq, e)),
+            };
+            let arrival = seqs.arrive(tp_recv.seq);
+            stat.arrival(arrival);
+            if tp_recv.seq == tp_sent.seq && arrival != Arrival::Duplicate {
+                break Some(tp_recv);
+            } else if tp_recv.seq < tp_sent.seq && arrival != Arrival::Duplicate {
+                stat.late();
+                debug!("late reply seq {} while waiting on seq {}", tp_recv.seq, tp_sent.seq);
+            } else {
+                warn!("server {} replied with seq {} to echo seq {} - {:?}", server_addr, tp_recv.seq, tp_sent.seq, arrival);
+            }
+        };
+        let mut tp_recv = match reply {
+            Some(tp_recv) => tp_recv,
+            None => {
+                let timed_out = || std::io::Error::new(ErrorKind::TimedOut, format!("no reply to echo seq {} within {:?}", tp_sent.seq, cli.timeout_socket));
+                let max = match cli.reconnect_after {
+                    Some(max) => max,
+                    None => {
+                        let e = anyhow::Error::from(timed_out()).context(format!("with IP server {} at read", server_addr));
+                        return Err(in_flight_lost(&mut stat, session, tp_sent.seq, e));
+                    },
+                };
+                // the reconnect this may lead to is a timeout too, so the outage and failure counts agree
+                stat.lost_to(outage::Cause::Timeout);
+                session.echo(tp_sent.seq, None);
+                lost_in_row += 1;
+                info!("echo seq {} lost - no reply from {} within {:?}", tp_sent.seq, server_addr, cli.timeout_socket);
+                if lost_in_row >= max {
+                    return Err(timed_out()).context(format!("with IP server {} at read - {} echos in a row lost", server_addr, lost_in_row));
+                }
+                continue;
+            },
+        };
+        lost_in_row = 0;
+        let dur = sent_at.elapsed();
+        tp_recv.client_recv = Some(util::unix_nanos());
+        record_one_way(&mut stat, &tp_recv);
 
-        stat.update(dur);
-        // info!("post echo {} ms", dur.as_millis());
-        if dur > cli.warn_threshold {
-            warn!("broke threshold - echo time: {:?}", &dur);
-        } else if dur > cli.info_threshold {
-            info!("broke info threshold - echo time: {:?}", &dur);
+        if let Some(outage) = stat.update(dur) {
+            session.outage(&outage);
         }
+        session.echo(tp_recv.seq, Some(dur));
+        // info!("post echo {} ms", dur.as_millis());
+        log_echo(cli, &session.target, &dur);
         if let Some(ref dur) = cli.interval {
-            util::sleep_until_even_interval(None, dur);
+            limit.sleep_until_even_interval(dur);
         }
-        if cli.human_time {
-            debug!("Returned packet in: {}", duration_to_human(&dur,2));
-        } else {
-            debug!("Returned packet in: {:.3}ms", &dur.as_secs_f64()*1000f64);
+    }
+}
+
+/// count the echo still in flight on a connection that just failed with `e` as lost to the cause of `e`
+///
+/// hands `e` back for the caller to return
+pub fn in_flight_lost(stat: &mut Stat, session: &Session, seq: u64, e: anyhow::Error) -> anyhow::Error {
+    stat.lost_to(outage::classify(&e));
+    session.echo(seq, None);
+    e
+}
+
+/// next whole echo off `stream` - None if none came in before `deadline` or shutdown was requested
+///
+/// bytes of an echo cut off by the deadline stay in `buf` so the stream stays in step for the next read
+fn read_echo(stream: &mut TcpStream, buf: &mut Vec<u8>, deadline: Instant) -> Result<Option<TimePacket>> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        match echo_codec().deserialize::<TimePacket>(buf) {
+            Ok(tp) => {
+                let len = echo_codec().serialized_size(&tp).context("sizing echo")? as usize;
+                buf.drain(..len);
+                return Ok(Some(tp));
+            },
+            Err(e) if matches!(*e, bincode::ErrorKind::Io(ref io) if io.kind() == ErrorKind::UnexpectedEof) => {},
+            Err(e) => return Err(e.into()),
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if shutdown::requested() || remaining == Duration::from_secs(0) {
+            return Ok(None);
+        }
+        stream.set_read_timeout(Some(remaining.min(SHUTDOWN_POLL))).context("setting read timeout of client socket")?;
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Ok(len) => buf.extend_from_slice(&chunk[..len]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {},
+            Err(e) => return Err(e.into()),
         }
     }
-    Ok(())
 }
 
+fn record_one_way(stat: &mut Stat, tp: &TimePacket) {
+    if let (Some(t2), Some(t3), Some(t4)) = (tp.server_recv, tp.server_send, tp.client_recv) {
+        let (fwd, ret) = stat.update_one_way(tp.client_send, t2, t3, t4);
+        debug!("one-way delays forward: {:.3}ms return: {:.3}ms", fwd as f64 / 1e6, ret as f64 / 1e6);
+    }
+}
+
+/// threshold and debug logging of a single echo time - shared by tcp and udp clients
+pub fn log_echo(cli: &ClientOpts, target: &str, dur: &Duration) {
+    if *dur > cli.warn_threshold {
+        warn!("{} broke threshold - echo time: {:?}", target, dur);
+    } else if *dur > cli.info_threshold {
+        info!("{} broke info threshold - echo time: {:?}", target, dur);
+    }
+    if cli.human_time {
+        debug!("Returned packet in: {}", duration_to_human(dur,2));
+    } else {
+        debug!("Returned packet in: {:.3}ms", dur.as_secs_f64()*1000f64);
+    }
+}
+
+/// stop the ticker after it reports whatever is in the current partial interval
 fn stop_ticker() {
+    STOP_TICKER.store(true, Ordering::Relaxed);
     let mut lock = COND_STOP.0.lock().unwrap();
     *lock = true;
     COND_STOP.1.notify_all();
 }
 
-fn spawn_ticker(cli: &Cli, dur: Duration, mut stat: Stat) {
+/// one ticker for all targets so their lines for an interval come out together
+fn spawn_ticker(cli: &ClientOpts, dur: Duration, mut targets: Vec<(String, Stat)>, mut outputs: tick::Outputs) -> JoinHandle<()> {
     {
+        STOP_TICKER.store(false, Ordering::Relaxed);
         let mut lock = COND_STOP.0.lock().unwrap();
         *lock = false;
     }
@@ -276,50 +501,119 @@ fn spawn_ticker(cli: &Cli, dur: Duration, mut stat: Stat) {
         .name("ticker".to_string())
         .spawn(move || {
             info!("stat ticker started");
+            let mut last_offsets: Vec<Option<ClockOffset>> = vec![None; targets.len()];
+            let mut last_tick = Instant::now();
+            let mut last_tick_wall = Utc::now();
             loop {
-                let mut tot_ticks = 0;
-                {
+                let stopping = {
                     let lock = COND_STOP.0.lock().unwrap();
                     if !*lock {
                         let dur_next = util::compute_until_even_interval_nanos(None, &dur);
                         let res = COND_STOP.1.wait_timeout(lock, dur_next).unwrap();
-                        if *res.0 {
-                            info!("stopping on check of condition during or interrupted sleep");
-                            break;
+                        if !*res.0 && !res.1.timed_out() {
+                            // woken early by a shutdown request - keep waiting for the tick or the stop
+                            continue;
                         }
+                        *res.0
                     } else {
                         debug!("stopping on initial check of condition before sleep");
-                        break;
-                    };
+                        true
+                    }
+                } || STOP_TICKER.load(Ordering::Relaxed);
+
+                let elapsed = last_tick.elapsed();
+                last_tick = Instant::now();
+                let now_wall = Utc::now();
+                for ((target, stat), last_offset) in targets.iter_mut().zip(last_offsets.iter_mut()) {
+                    let snap = stat.snap_shot();
+                    report_tick(&cli, target, elapsed, &snap, last_offset);
+                    outputs.send(target, last_tick_wall, now_wall, elapsed, &snap, stat);
                 }
-                if STOP_TICKER.load(Ordering::Relaxed) == true {
-                    info!("tic stopped");
+                last_tick_wall = now_wall;
+                if stopping {
+                    info!("tic stopped after flushing last partial interval");
                     break;
                 }
-                let (echos, tot_time, max_time, min_time) = stat.snap_shot();
-                let rate = (echos) as f64 / dur.as_secs() as f64;
-                tot_ticks += echos;
-                if echos <= 0 {
-                    info!("No echo stats to report - no working echos");
-                } else {
-                    let avg_ms = Duration::from_nanos((tot_time.as_nanos() / echos as u128) as u64);
-                    if cli.human_time {
-                        info!("echos: {} rate: {} max time: {} avg time: {} min time: {}", tot_ticks
-                              , util::greek(rate)
-                              , duration_to_human(&max_time, 2)
-                              , duration_to_human(&avg_ms, 2)
-                              , duration_to_human(&min_time, 2));
-                    } else {
-                        info!("echos: {} rate: {} max time: {:.3}ms avg time: {:.3}ms min time: {:.3}ms", tot_ticks
-                              , util::greek(rate)
-                              , max_time.as_secs_f64() * 1000f64
-                              , avg_ms.as_secs_f64() * 1000f64
-                              , min_time.as_secs_f64() * 1000f64);
-                    }
-                }
             }
+            outputs.close();
         })
-        .unwrap();
+        .unwrap()
+}
+
+/// one ticker line for the interval of length `elapsed` that `snap` covers
+fn report_tick(cli: &ClientOpts, target: &str, elapsed: Duration, snap: &StatSnapshot, last_offset: &mut Option<ClockOffset>) {
+    let rate = (snap.echos) as f64 / elapsed.as_secs_f64();
+    let tot_ticks = snap.echos;
+    let mut loss = format!(" lost: {} late: {} dup: {} reord: {} outages: {} avail: {:.3}%", snap.lost, snap.late, snap.dup, snap.reordered
+                           , snap.outages, snap.availability_pct);
+    let causes = snap.failures_by_cause();
+    if !causes.is_empty() {
+        let causes: Vec<String> = causes.iter().map(|(cause, n)| format!("{} {}", cause, n)).collect();
+        loss.push_str(&format!(" failures: {}", causes.join(" ")));
+    }
+    if snap.ipdv.count > 0 {
+        loss.push_str(&format!(" jitter: {:.3}ms ipdv max/min: {:.3}/{:.3}ms"
+                               , snap.jitter_nanos as f64 / 1e6
+                               , snap.ipdv.max_nanos as f64 / 1e6
+                               , snap.ipdv.min_nanos as f64 / 1e6));
+    }
+    if cli.one_way || cli.clock_offset {
+        loss.push_str(&format!(" fwd {} ret {}", one_way_to_str(&snap.fwd), one_way_to_str(&snap.ret)));
+    }
+    if let Some(off) = snap.offset {
+        loss.push_str(&format!(" offset: {:.3}ms +/-{:.3}ms", off.offset_nanos as f64 / 1e6, off.error_nanos as f64 / 1e6));
+        if let Some(last) = last_offset {
+            let drift = (off.offset_nanos - last.offset_nanos) as f64 / elapsed.as_secs_f64();
+            loss.push_str(&format!(" drift: {:.3}us/s", drift / 1e3));
+        }
+        let limit = cli.offset_unstable.as_nanos() as i64;
+        if off.jitter_nanos > limit || off.error_nanos > limit {
+            warn!("{} clock offset estimate unstable - jitter: {:.3}ms error bound: {:.3}ms over {} echos - one-way delays are not trustworthy"
+                  , target, off.jitter_nanos as f64 / 1e6, off.error_nanos as f64 / 1e6, off.samples);
+        }
+        *last_offset = Some(off);
+    }
+    if snap.echos == 0 {
+        info!("{} No echo stats to report - no working echos{}", target, loss);
+    } else {
+        let avg_ms = snap.avg_time().unwrap_or_default();
+        if cli.human_time {
+            info!("{} echos: {} rate: {} max time: {} avg time: {} min time: {} p50: {} p90: {} p99: {} p99.9: {} stddev: {}{}", target, tot_ticks
+                  , util::greek(rate)
+                  , duration_to_human(&snap.max_time, 2)
+                  , duration_to_human(&avg_ms, 2)
+                  , duration_to_human(&snap.min_time, 2)
+                  , duration_to_human(&snap.percentile(0.50), 2)
+                  , duration_to_human(&snap.percentile(0.90), 2)
+                  , duration_to_human(&snap.percentile(0.99), 2)
+                  , duration_to_human(&snap.percentile(0.999), 2)
+                  , duration_to_human(&snap.stddev(), 2)
+                  , loss);
+        } else {
+            info!("{} echos: {} rate: {} max time: {:.3}ms avg time: {:.3}ms min time: {:.3}ms p50: {:.3}ms p90: {:.3}ms p99: {:.3}ms p99.9: {:.3}ms stddev: {:.3}ms{}", target, tot_ticks
+                  , util::greek(rate)
+                  , snap.max_time.as_secs_f64() * 1000f64
+                  , avg_ms.as_secs_f64() * 1000f64
+                  , snap.min_time.as_secs_f64() * 1000f64
+                  , snap.percentile(0.50).as_secs_f64() * 1000f64
+                  , snap.percentile(0.90).as_secs_f64() * 1000f64
+                  , snap.percentile(0.99).as_secs_f64() * 1000f64
+                  , snap.percentile(0.999).as_secs_f64() * 1000f64
+                  , snap.stddev().as_secs_f64() * 1000f64
+                  , loss);
+        }
+    }
+}
+
+/// max/avg/min of a one-way delay in float milliseconds - can be negative when clocks disagree
+fn one_way_to_str(d: &SignedDelay) -> String {
+    match d.avg_nanos() {
+        None => "n/a".to_string(),
+        Some(avg) => format!("max/avg/min: {:.3}/{:.3}/{:.3}ms"
+                             , d.max_nanos as f64 / 1e6
+                             , avg as f64 / 1e6
+                             , d.min_nanos as f64 / 1e6),
+    }
 }
 
 pub fn duration_to_human(dur: &Duration, prec: u32) -> String {
@@ -336,19 +630,19 @@ pub fn duration_to_human(dur: &Duration, prec: u32) -> String {
             }
             num -= m * conv.0;
             str.push_str(&format!("{}{}", m, conv.1));
-            if prec_remaining <= 0 {
+            if prec_remaining == 0 {
                 break;
             }
         }
     }
-    return str;
+    str
 }
 
 struct MyDuration(Duration);
 
-impl Into<Duration> for MyDuration {
-    fn into(self) -> Duration {
-        self.0
+impl From<MyDuration> for Duration {
+    fn from(d: MyDuration) -> Duration {
+        d.0
     }
 }
 
@@ -358,18 +652,14 @@ impl std::fmt::Display for MyDuration {
         const TIME_UNITS: &[(u128,&str)] = &[(1_000_000_000, "s"), (1_000_000, "ms"),(1_000, "u"),(1, "ns")];
         let mut num = self.0.as_nanos();
         let mut str = String::new();
-        let mut prec_remaining = if let Some(prec) = f.precision() {
-             prec
-        } else {
-            2
-        };
+        let mut prec_remaining = f.precision().unwrap_or(2);
         for (i,conv) in TIME_UNITS.iter().enumerate() {
             if num > conv.0 {
                 let m = num / conv.0;
                 num -= m * conv.0;
-                write!(f, "{}{}", m, conv.1);
+                write!(f, "{}{}", m, conv.1)?;
                 prec_remaining -= 1;
-                if prec_remaining <= 0 {
+                if prec_remaining == 0 {
                     break;
                 }
             }
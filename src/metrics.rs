@@ -0,0 +1,164 @@
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::Context;
+use log::{debug, info};
+
+use crate::outage::Cause;
+use crate::stat::{Stat, StatSnapshot};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// upper bounds in seconds of the echo time histogram buckets - +Inf is added on top
+pub const RTT_BUCKETS: &[f64] = &[0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// name, help and value of a per target counter
+pub type Counter = (&'static str, &'static str, fn(&StatSnapshot) -> u64);
+
+pub const COUNTERS: &[Counter] = &[
+    ("netdelay_echos_sent_total", "echos sent whether they came back or not", |s| s.sent),
+    ("netdelay_echos_total", "echos that came back", |s| s.echos),
+    ("netdelay_echos_lost_total", "echos that never came back", |s| s.lost),
+    ("netdelay_echos_late_total", "replies after their echo was counted as lost", |s| s.late),
+    ("netdelay_echos_duplicate_total", "replies seen more than once", |s| s.dup),
+    ("netdelay_echos_reordered_total", "replies that arrived out of order", |s| s.reordered),
+    ("netdelay_connects_total", "connections made to the target", |s| s.connects),
+    ("netdelay_connection_failures_total", "failed connection attempts plus connections lost after being made", |s| s.conn_failures),
+];
+
+/// name and help of the connection failure counter labelled by `cause` as well as `target`
+pub const FAILURE_CAUSES: (&str, &str) = ("netdelay_connection_failure_causes_total", "connection failures by cause - refused, reset, timeout, dns, protocol, unreachable or other");
+
+/// scrapes that take longer than this are dropped
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// every target's `Stat` exposed in the Prometheus text format on --metrics-addr
+#[derive(Clone)]
+pub struct Metrics {
+    targets: Arc<Mutex<Vec<(String, Stat)>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            targets: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    pub fn add(&self, target: &str, stat: Stat) {
+        self.targets.lock().expect("Unable to update Metrics at lock").push((target.to_string(), stat));
+    }
+
+    /// bind now so a bad address fails the run then answer scrapes on a thread of its own
+    pub fn serve(&self, addr: &SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr).with_context(|| format!("binding metrics listener to {}", addr))?;
+        info!("metrics listening on http://{}/metrics", addr);
+        let metrics = self.clone();
+        std::thread::Builder::new()
+            .name("metrics".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let res = stream.context("accepting scrape").and_then(|s| metrics.scrape(s));
+                    if let Err(e) = res {
+                        debug!("metrics scrape failed: {}", crate::single_line_error(&e));
+                    }
+                }
+            })
+            .context("spawning metrics thread")?;
+        Ok(())
+    }
+
+    /// answer one http request - only GET /metrics is served
+    fn scrape(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(SCRAPE_TIMEOUT)).context("setting scrape read timeout")?;
+        stream.set_write_timeout(Some(SCRAPE_TIMEOUT)).context("setting scrape write timeout")?;
+        let mut req = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !req.windows(4).any(|w| w == b"\r\n\r\n") && req.len() < 16 * 1024 {
+            let n = stream.read(&mut buf).context("reading scrape request")?;
+            if n == 0 {
+                break;
+            }
+            req.extend_from_slice(&buf[..n]);
+        }
+        let req = String::from_utf8_lossy(&req);
+        let mut words = req.split_whitespace();
+        let (status, body) = match (words.next(), words.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render()),
+            (Some("GET"), _) => ("404 Not Found", "only /metrics is served\n".to_string()),
+            _ => ("405 Method Not Allowed", "only GET is served\n".to_string()),
+        };
+        write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}"
+               , status, body.len(), body).context("writing scrape response")?;
+        stream.flush().context("flushing scrape response")?;
+        Ok(())
+    }
+
+    /// the whole /metrics page
+    pub fn render(&self) -> String {
+        let snaps: Vec<(String, StatSnapshot)> = self.targets.lock().expect("Unable to read Metrics at lock")
+            .iter().map(|(t, s)| (t.clone(), s.summary())).collect();
+        let mut out = String::new();
+        header(&mut out, "netdelay_build_info", "gauge", "version and git revision NetDelay was built from");
+        let _ = writeln!(out, "netdelay_build_info{{version=\"{}\",revision=\"{}\"}} 1", env!("CARGO_PKG_VERSION"), label(env!("BUILD_GIT_HASH")));
+
+        header(&mut out, "netdelay_rtt_seconds", "histogram", "echo round trip time");
+        for (target, snap) in snaps.iter() {
+            rtt_histogram(&mut out, target, snap);
+        }
+        for (name, help, value) in COUNTERS {
+            header(&mut out, name, "counter", help);
+            for (target, snap) in snaps.iter() {
+                let _ = writeln!(out, "{}{{target=\"{}\"}} {}", name, label(target), value(snap));
+            }
+        }
+        header(&mut out, FAILURE_CAUSES.0, "counter", FAILURE_CAUSES.1);
+        for (target, snap) in snaps.iter() {
+            for (cause, n) in Cause::FAILURES.iter().zip(snap.failure_causes) {
+                let _ = writeln!(out, "{}{{target=\"{}\",cause=\"{}\"}} {}", FAILURE_CAUSES.0, label(target), cause, n);
+            }
+        }
+        header(&mut out, "netdelay_max_outage_seconds", "gauge", "longest time from a failure to the next echo that came back");
+        for (target, snap) in snaps.iter() {
+            let _ = writeln!(out, "netdelay_max_outage_seconds{{target=\"{}\"}} {}", label(target), snap.max_outage.as_secs_f64());
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// echos per RTT_BUCKETS bucket plus one more for those over the last bound - not cumulative
+///
+/// taken from the HDR histogram so an echo time right on a bound is placed to within its 3 significant figures
+pub fn rtt_bucket_counts(snap: &StatSnapshot) -> Vec<u64> {
+    let mut counts = vec![0u64; RTT_BUCKETS.len() + 1];
+    for v in snap.hist.iter_recorded() {
+        let secs = snap.hist.median_equivalent(v.value_iterated_to()) as f64 / 1e9;
+        let i = RTT_BUCKETS.iter().position(|bound| secs <= *bound).unwrap_or(RTT_BUCKETS.len());
+        counts[i] += v.count_at_value();
+    }
+    counts
+}
+
+fn rtt_histogram(out: &mut String, target: &str, snap: &StatSnapshot) {
+    let target = label(target);
+    let mut cumulative = 0;
+    for (bound, count) in RTT_BUCKETS.iter().zip(rtt_bucket_counts(snap)) {
+        cumulative += count;
+        let _ = writeln!(out, "netdelay_rtt_seconds_bucket{{target=\"{}\",le=\"{}\"}} {}", target, bound, cumulative);
+    }
+    let _ = writeln!(out, "netdelay_rtt_seconds_bucket{{target=\"{}\",le=\"+Inf\"}} {}", target, snap.echos);
+    let _ = writeln!(out, "netdelay_rtt_seconds_sum{{target=\"{}\"}} {}", target, snap.tot_time.as_secs_f64());
+    let _ = writeln!(out, "netdelay_rtt_seconds_count{{target=\"{}\"}} {}", target, snap.echos);
+}
+
+/// escape a label value
+fn label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
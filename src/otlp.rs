@@ -0,0 +1,300 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use anyhow::{anyhow, Context};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+
+use crate::metrics::{COUNTERS, FAILURE_CAUSES, RTT_BUCKETS, rtt_bucket_counts};
+use crate::outage::Cause;
+use crate::stat::StatSnapshot;
+use crate::util;
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// where an OTLP/HTTP collector takes metrics - only plain http is spoken
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    /// host:port as given, sent as the Host header
+    pub authority: String,
+    pub path: String,
+}
+
+/// `http://host:port[/path]` - the path defaults to the OTLP standard /v1/metrics
+pub fn endpoint_from_str(s: &str) -> Result<Endpoint> {
+    let rest = s.strip_prefix("http://")
+        .ok_or_else(|| anyhow!("OTLP endpoint \"{}\" must start with http:// - https is not supported", s))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) if i + 1 < rest.len() => (&rest[..i], &rest[i..]),
+        Some(i) => (&rest[..i], "/v1/metrics"),
+        None => (rest, "/v1/metrics"),
+    };
+    if authority.is_empty() {
+        return Err(anyhow!("OTLP endpoint \"{}\" has no host", s));
+    }
+    let authority = if authority.contains(':') && !authority.ends_with(']') { authority.to_string() } else { format!("{}:4318", authority) };
+    Ok(Endpoint { authority, path: path.to_string() })
+}
+
+/// posts the run's totals for a target as OTLP JSON to a collector on every ticker interval
+pub struct OtlpExporter {
+    endpoint: Endpoint,
+    /// resource attributes every export carries
+    host: String,
+    target: String,
+    /// start of the cumulative sums and histogram
+    start_nanos: u64,
+    timeout: Duration,
+}
+
+impl OtlpExporter {
+    pub fn new(endpoint: Endpoint, host: &str, target: &str, timeout: Duration) -> Self {
+        info!("exporting OTLP metrics to http://{}{}", endpoint.authority, endpoint.path);
+        OtlpExporter {
+            endpoint,
+            host: host.to_string(),
+            target: target.to_string(),
+            start_nanos: util::unix_nanos(),
+            timeout,
+        }
+    }
+
+    /// export the totals in `snap` - a failure is only logged so the collector being down never stops echos
+    pub fn export(&self, snap: &StatSnapshot) {
+        let body = self.render(snap).to_string();
+        if let Err(e) = self.post(&body) {
+            warn!("unable to export OTLP metrics to {}: {}", self.endpoint.authority, crate::single_line_error(&e));
+        }
+    }
+
+    /// an ExportMetricsServiceRequest in the protobuf JSON mapping - 64 bit integers go as strings
+    fn render(&self, snap: &StatSnapshot) -> Value {
+        let now = util::unix_nanos();
+        let attr = |k: &str, v: &str| json!({"key": k, "value": {"stringValue": v}});
+        let mut metrics = vec![];
+
+        let mut hist_point = json!({
+            "startTimeUnixNano": self.start_nanos.to_string(),
+            "timeUnixNano": now.to_string(),
+            "count": snap.echos.to_string(),
+            "sum": snap.tot_time.as_secs_f64(),
+            "bucketCounts": rtt_bucket_counts(snap).iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+            "explicitBounds": RTT_BUCKETS,
+        });
+        if snap.echos > 0 {
+            hist_point["min"] = json!(snap.min_time.as_secs_f64());
+            hist_point["max"] = json!(snap.max_time.as_secs_f64());
+        }
+        metrics.push(json!({
+            "name": "netdelay.rtt",
+            "description": "echo round trip time",
+            "unit": "s",
+            "histogram": {
+                "aggregationTemporality": 2,
+                "dataPoints": [hist_point],
+            },
+        }));
+
+        for (name, help, value) in COUNTERS {
+            metrics.push(json!({
+                "name": otel_name(name),
+                "description": help,
+                "unit": "1",
+                "sum": {
+                    "aggregationTemporality": 2,
+                    "isMonotonic": true,
+                    "dataPoints": [{
+                        "startTimeUnixNano": self.start_nanos.to_string(),
+                        "timeUnixNano": now.to_string(),
+                        "asInt": value(snap).to_string(),
+                    }],
+                },
+            }));
+        }
+
+        let points: Vec<Value> = Cause::FAILURES.iter().zip(snap.failure_causes).map(|(cause, n)| json!({
+            "startTimeUnixNano": self.start_nanos.to_string(),
+            "timeUnixNano": now.to_string(),
+            "asInt": n.to_string(),
+            "attributes": [attr("cause", cause.as_str())],
+        })).collect();
+        metrics.push(json!({
+            "name": otel_name(FAILURE_CAUSES.0),
+            "description": FAILURE_CAUSES.1,
+            "unit": "1",
+            "sum": {
+                "aggregationTemporality": 2,
+                "isMonotonic": true,
+                "dataPoints": points,
+            },
+        }));
+
+        json!({
+            "resourceMetrics": [{
+                "resource": {
+                    "attributes": [
+                        attr("service.name", "netdelay"),
+                        attr("service.version", env!("CARGO_PKG_VERSION")),
+                        attr("vcs.revision", env!("BUILD_GIT_HASH")),
+                        attr("host.name", &self.host),
+                        attr("netdelay.target", &self.target),
+                    ],
+                },
+                "scopeMetrics": [{
+                    "scope": {"name": "netdelay", "version": env!("CARGO_PKG_VERSION")},
+                    "metrics": metrics,
+                }],
+            }],
+        })
+    }
+
+    /// one HTTP/1.1 POST on a fresh connection - exports are rare enough not to keep it open
+    fn post(&self, body: &str) -> Result<()> {
+        let addr: SocketAddr = self.endpoint.authority.to_socket_addrs()
+            .with_context(|| format!("resolving OTLP collector {}", self.endpoint.authority))?
+            .next()
+            .ok_or_else(|| anyhow!("empty result from DNS lookup for: {}", self.endpoint.authority))?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout).context("connecting to OTLP collector")?;
+        stream.set_read_timeout(Some(self.timeout)).context("setting read timeout of OTLP stream")?;
+        stream.set_write_timeout(Some(self.timeout)).context("setting write timeout of OTLP stream")?;
+        write!(stream, "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}"
+               , self.endpoint.path, self.endpoint.authority, body.len(), body).context("writing OTLP request")?;
+        stream.flush().context("flushing OTLP request")?;
+
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).context("reading OTLP response")?;
+        let status = resp.split_whitespace().nth(1).unwrap_or("");
+        if !status.starts_with('2') {
+            return Err(anyhow!("collector answered: {}", resp.lines().next().unwrap_or("nothing")));
+        }
+        debug!("OTLP export of {} bytes accepted with status {}", body.len(), status);
+        Ok(())
+    }
+}
+
+/// netdelay_echos_sent_total becomes netdelay.echos.sent - OTel names carry no _total and use dots
+fn otel_name(prometheus_name: &str) -> String {
+    prometheus_name.trim_end_matches("_total").replace('_', ".")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+    use crate::stat::Stat;
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn endpoint_defaults_port_and_path() {
+        let e = endpoint_from_str("http://otel-collector").unwrap();
+        assert_eq!((e.authority.as_str(), e.path.as_str()), ("otel-collector:4318", "/v1/metrics"));
+        let e = endpoint_from_str("http://otel:9999/").unwrap();
+        assert_eq!((e.authority.as_str(), e.path.as_str()), ("otel:9999", "/v1/metrics"));
+        let e = endpoint_from_str("http://[::1]/custom/metrics").unwrap();
+        assert_eq!((e.authority.as_str(), e.path.as_str()), ("[::1]:4318", "/custom/metrics"));
+        let e = endpoint_from_str("http://[::1]:4000").unwrap();
+        assert_eq!(e.authority, "[::1]:4000");
+    }
+
+    #[test]
+    fn endpoint_must_be_plain_http_with_a_host() {
+        assert!(endpoint_from_str("https://otel:4318").is_err());
+        assert!(endpoint_from_str("otel:4318").is_err());
+        assert!(endpoint_from_str("http:///v1/metrics").is_err());
+    }
+
+    /// answer one POST with `status` and hand back its request line and body
+    fn collector(status: &'static str) -> (Endpoint, JoinHandle<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = endpoint_from_str(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+            let mut req = Vec::new();
+            let mut buf = [0u8; 4096];
+            let head_end = loop {
+                let n = stream.read(&mut buf).unwrap();
+                assert!(n > 0, "request cut short");
+                req.extend_from_slice(&buf[..n]);
+                if let Some(i) = req.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break i + 4;
+                }
+            };
+            let head = String::from_utf8(req[..head_end].to_vec()).unwrap();
+            let len: usize = head.lines()
+                .find_map(|l| l.strip_prefix("Content-Length: "))
+                .unwrap().trim().parse().unwrap();
+            while req.len() < head_end + len {
+                let n = stream.read(&mut buf).unwrap();
+                assert!(n > 0, "body cut short");
+                req.extend_from_slice(&buf[..n]);
+            }
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+            let body = String::from_utf8(req[head_end..].to_vec()).unwrap();
+            (head.lines().next().unwrap().to_string(), body)
+        });
+        (endpoint, handle)
+    }
+
+    fn snap() -> StatSnapshot {
+        let mut stat = Stat::new();
+        for ms in [1, 3, 700] {
+            stat.sent();
+            stat.update(Duration::from_millis(ms));
+        }
+        stat.conn_failure(Cause::Timeout);
+        stat.summary()
+    }
+
+    fn metric<'a>(metrics: &'a [Value], name: &str) -> &'a Value {
+        metrics.iter().find(|m| m["name"] == name).unwrap_or_else(|| panic!("no metric {}", name))
+    }
+
+    #[test]
+    fn export_posts_histogram_counters_and_attributes() {
+        let (endpoint, collector) = collector("200 OK");
+        let exporter = OtlpExporter::new(endpoint.clone(), "probe-host", "dc1", TIMEOUT);
+        let snap = snap();
+        exporter.post(&exporter.render(&snap).to_string()).unwrap();
+        let (request_line, body) = collector.join().unwrap();
+        assert_eq!(request_line, "POST /v1/metrics HTTP/1.1");
+
+        let req: Value = serde_json::from_str(&body).unwrap();
+        let resource = &req["resourceMetrics"][0];
+        let attrs = resource["resource"]["attributes"].as_array().unwrap();
+        let attr = |k: &str| attrs.iter().find(|a| a["key"] == k).map(|a| a["value"]["stringValue"].clone());
+        assert_eq!(attr("service.name"), Some(json!("netdelay")));
+        assert_eq!(attr("host.name"), Some(json!("probe-host")));
+        assert_eq!(attr("netdelay.target"), Some(json!("dc1")));
+        assert!(attr("service.version").is_some());
+
+        let metrics = resource["scopeMetrics"][0]["metrics"].as_array().unwrap();
+        let hist = &metric(metrics, "netdelay.rtt")["histogram"]["dataPoints"][0];
+        let counts = hist["bucketCounts"].as_array().unwrap();
+        assert_eq!(counts.len(), RTT_BUCKETS.len() + 1);
+        assert_eq!(hist["explicitBounds"].as_array().unwrap().len(), RTT_BUCKETS.len());
+        assert_eq!(hist["count"], "3");
+        let total: u64 = counts.iter().map(|c| c.as_str().unwrap().parse::<u64>().unwrap()).sum();
+        assert_eq!(total, 3);
+
+        let sent = &metric(metrics, "netdelay.echos.sent")["sum"];
+        assert_eq!(sent["isMonotonic"], true);
+        assert_eq!(sent["dataPoints"][0]["asInt"], "3");
+
+        let causes = metric(metrics, "netdelay.connection.failure.causes")["sum"]["dataPoints"].as_array().unwrap();
+        assert_eq!(causes.len(), Cause::FAILURES.len());
+        let timeout = causes.iter().find(|p| p["attributes"][0]["value"]["stringValue"] == "timeout").unwrap();
+        assert_eq!(timeout["asInt"], "1");
+    }
+
+    #[test]
+    fn collector_error_status_fails_the_post() {
+        let (endpoint, collector) = collector("503 Service Unavailable");
+        let exporter = OtlpExporter::new(endpoint, "probe-host", "dc1", TIMEOUT);
+        let res = exporter.post(&exporter.render(&snap()).to_string());
+        collector.join().unwrap();
+        assert!(res.unwrap_err().to_string().contains("503"));
+    }
+}
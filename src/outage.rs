@@ -0,0 +1,189 @@
+use std::fmt;
+use std::io::ErrorKind;
+use std::time::Duration;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::warn;
+use serde::Serialize;
+
+use crate::dial::LookupFailed;
+use crate::duration_to_human;
+use crate::sink::{Record, Sink, csv_field};
+
+/// what went wrong first when a target stopped answering
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Cause {
+    /// nothing listening - the server is down or restarting
+    Refused,
+    /// the connection was closed or reset after being made
+    Reset,
+    /// no answer in time - often a firewall dropping packets
+    Timeout,
+    /// the host name could not be looked up
+    Dns,
+    /// the server sent something that is not an echo
+    Protocol,
+    /// no route to the host or network
+    Unreachable,
+    /// udp echos went missing while the socket itself was fine - a tcp echo with no reply in time is a Timeout
+    Loss,
+    Other,
+}
+
+impl Cause {
+    /// the causes a failed connect or lost connection can have, in the order they are reported
+    pub const FAILURES: [Cause; 7] = [Cause::Refused, Cause::Reset, Cause::Timeout, Cause::Dns, Cause::Protocol, Cause::Unreachable, Cause::Other];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Cause::Refused => "refused",
+            Cause::Reset => "reset",
+            Cause::Timeout => "timeout",
+            Cause::Dns => "dns",
+            Cause::Protocol => "protocol",
+            Cause::Unreachable => "unreachable",
+            Cause::Loss => "loss",
+            Cause::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+fn from_io(kind: ErrorKind) -> Cause {
+    match kind {
+        ErrorKind::ConnectionRefused => Cause::Refused,
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof => Cause::Reset,
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => Cause::Timeout,
+        ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable | ErrorKind::NetworkDown | ErrorKind::AddrNotAvailable => Cause::Unreachable,
+        _ => Cause::Other,
+    }
+}
+
+/// the cause of a failed connect or a connection lost after being made
+pub fn classify(e: &anyhow::Error) -> Cause {
+    if e.downcast_ref::<LookupFailed>().is_some() {
+        return Cause::Dns;
+    }
+    for err in e.chain() {
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            return from_io(io.kind());
+        }
+        if let Some(b) = err.downcast_ref::<bincode::Error>() {
+            return match **b {
+                bincode::ErrorKind::Io(ref io) => from_io(io.kind()),
+                _ => Cause::Protocol,
+            };
+        }
+    }
+    Cause::Other
+}
+
+/// time a target was unreachable - first failure to the next echo that came back
+#[derive(Debug, Clone)]
+pub struct Outage {
+    pub start: DateTime<Utc>,
+    pub duration: Duration,
+    /// the first failure of the outage
+    pub cause: Cause,
+    pub conn_failures: u64,
+    pub lost: u64,
+    /// still going on when the client stopped
+    pub ongoing: bool,
+}
+
+/// one line of --outage-out
+#[derive(Serialize)]
+struct OutageRecord<'a> {
+    /// RFC 3339 UTC start and end of the outage
+    start: String,
+    end: String,
+    target: &'a str,
+    duration_ns: u64,
+    cause: Cause,
+    conn_failures: u64,
+    lost: u64,
+    ongoing: bool,
+}
+
+impl Record for OutageRecord<'_> {
+    fn csv_header() -> &'static str {
+        "start,end,target,duration_ns,cause,conn_failures,lost,ongoing"
+    }
+
+    fn csv_row(&self) -> String {
+        format!("{},{},{},{},{},{},{},{}", self.start, self.end, csv_field(self.target), self.duration_ns
+                , self.cause, self.conn_failures, self.lost, self.ongoing)
+    }
+}
+
+/// log an outage of `target` and write it to --outage-out
+pub fn report(target: &str, outage: &Outage, outage_out: Option<&Sink>) {
+    warn!("{} outage of {} {} - cause: {} connection failures: {} lost echos: {}"
+          , target, duration_to_human(&outage.duration, 2), if outage.ongoing { "still going at the end" } else { "ended" }
+          , outage.cause, outage.conn_failures, outage.lost);
+    if let Some(sink) = outage_out {
+        let end = outage.start + chrono::Duration::from_std(outage.duration).unwrap_or_default();
+        let rec = OutageRecord {
+            start: outage.start.to_rfc3339_opts(SecondsFormat::Millis, true),
+            end: end.to_rfc3339_opts(SecondsFormat::Millis, true),
+            target,
+            duration_ns: outage.duration.as_nanos() as u64,
+            cause: outage.cause,
+            conn_failures: outage.conn_failures,
+            lost: outage.lost,
+            ongoing: outage.ongoing,
+        };
+        if let Err(e) = sink.write(&rec) {
+            warn!("unable to write outage record: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use crate::dial::HostPort;
+    use super::*;
+
+    fn io(kind: ErrorKind) -> anyhow::Error {
+        std::io::Error::from(kind).into()
+    }
+
+    #[test]
+    fn io_errors_by_kind() {
+        for (kind, cause) in [(ErrorKind::ConnectionRefused, Cause::Refused), (ErrorKind::ConnectionReset, Cause::Reset)
+                              , (ErrorKind::UnexpectedEof, Cause::Reset), (ErrorKind::TimedOut, Cause::Timeout)
+                              , (ErrorKind::WouldBlock, Cause::Timeout), (ErrorKind::HostUnreachable, Cause::Unreachable)
+                              , (ErrorKind::NetworkUnreachable, Cause::Unreachable), (ErrorKind::PermissionDenied, Cause::Other)] {
+            assert_eq!(classify(&io(kind)), cause, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn context_is_looked_through() {
+        let e = Err::<(), _>(std::io::Error::from(ErrorKind::ConnectionRefused))
+            .context("connecting to 10.0.0.1:5150").context("unable to connect to any of 10.0.0.1:5150").unwrap_err();
+        assert_eq!(classify(&e), Cause::Refused);
+        assert_eq!(classify(&anyhow::anyhow!("shutting down while connecting")), Cause::Other);
+    }
+
+    #[test]
+    fn failed_lookup_is_dns() {
+        let e = HostPort { host: "no-such-host.invalid".to_string(), port: 5150 }.resolve().unwrap_err();
+        assert_eq!(classify(&e), Cause::Dns);
+        assert_eq!(classify(&e.context("connecting")), Cause::Dns);
+    }
+
+    #[test]
+    fn bincode_errors() {
+        let eof: bincode::Error = Box::new(bincode::ErrorKind::Io(std::io::Error::from(ErrorKind::UnexpectedEof)));
+        assert_eq!(classify(&anyhow::Error::from(eof).context("with IP server 10.0.0.1:5150 at read")), Cause::Reset);
+        let bad: bincode::Error = Box::new(bincode::ErrorKind::InvalidTagEncoding(7));
+        assert_eq!(classify(&anyhow::Error::from(bad).context("with IP server 10.0.0.1:5150 at read")), Cause::Protocol);
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use log::info;
+
+use crate::duration_to_human;
+
+/// what the server did for one client connection - or one udp peer address
+struct PeerTotals {
+    addr: SocketAddr,
+    first_seen: Instant,
+    last_seen: Instant,
+    echos: u64,
+    bytes: u64,
+    closed: bool,
+}
+
+/// server side echo totals per client, reported when the server shuts down
+#[derive(Clone)]
+pub struct Peers {
+    inner: Arc<Mutex<BTreeMap<String, PeerTotals>>>,
+}
+
+impl Peers {
+    pub fn new() -> Self {
+        Peers {
+            inner: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    pub fn echo(&self, id: &str, addr: &SocketAddr, bytes: usize) {
+        let mut lock = self.inner.lock().expect("Unable to update Peers at lock");
+        let now = Instant::now();
+        let peer = lock.entry(id.to_string()).or_insert_with(|| PeerTotals {
+            addr: *addr,
+            first_seen: now,
+            last_seen: now,
+            echos: 0,
+            bytes: 0,
+            closed: false,
+        });
+        peer.last_seen = now;
+        peer.echos += 1;
+        peer.bytes += bytes as u64;
+    }
+
+    pub fn closed(&self, id: &str) {
+        if let Some(peer) = self.inner.lock().expect("Unable to update Peers at lock").get_mut(id) {
+            peer.closed = true;
+        }
+    }
+
+    pub fn report(&self) {
+        let lock = self.inner.lock().expect("Unable to report Peers at lock");
+        info!("served {} clients", lock.len());
+        for (id, peer) in lock.iter() {
+            info!("{} from {} echos: {} bytes: {} connected for: {}{}", id, peer.addr, peer.echos, peer.bytes
+                  , duration_to_human(&(peer.last_seen - peer.first_seen), 2)
+                  , if peer.closed { " (closed)" } else { "" });
+        }
+    }
+}
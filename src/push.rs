@@ -0,0 +1,298 @@
+use std::fmt::Write as _;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use anyhow::{anyhow, Context};
+use log::{info, warn};
+
+use crate::outage::Cause;
+use crate::stat::StatSnapshot;
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushFormat {
+    /// InfluxDB line protocol - one line with client and target tags
+    Influx,
+    /// StatsD counters and gauges named netdelay.<client>.<target>.<stat>
+    Statsd,
+    /// Graphite plaintext lines named netdelay.<client>.<target>.<stat>
+    Graphite,
+}
+
+impl FromStr for PushFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "influx" | "influxdb" => Ok(PushFormat::Influx),
+            "statsd" => Ok(PushFormat::Statsd),
+            "graphite" => Ok(PushFormat::Graphite),
+            _ => Err(anyhow!("push format must be one of influx, statsd or graphite but got {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "udp" => Ok(Transport::Udp),
+            "tcp" => Ok(Transport::Tcp),
+            _ => Err(anyhow!("push protocol must be udp or tcp but got {}", s)),
+        }
+    }
+}
+
+/// `host:port` of a push endpoint - unlike the client address the port is not optional
+pub fn push_addr_from_str(s: &str) -> Result<SocketAddr> {
+    s.to_socket_addrs()
+        .with_context(|| format!("Unable to get push address from {} - must be host:port", s))?
+        .next()
+        .ok_or_else(|| anyhow!("empty result from DNS lookup for: {}", s))
+}
+
+/// name this client pushes under - the host name unless --client-name says otherwise
+pub fn default_client_name() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// sends every ticker snapshot to a push based collector
+pub struct Pusher {
+    addr: SocketAddr,
+    format: PushFormat,
+    transport: Transport,
+    client: String,
+    udp: Option<UdpSocket>,
+    /// tcp connection made on first push and remade on the push after it breaks
+    tcp: Option<TcpStream>,
+    timeout: Duration,
+}
+
+/// the connection failure counts of an interval, named in the order of Cause::FAILURES
+const FAILURE_FIELDS: [&str; Cause::FAILURES.len()] = ["failures_refused", "failures_reset", "failures_timeout", "failures_dns"
+    , "failures_protocol", "failures_unreachable", "failures_other"];
+
+impl Pusher {
+    pub fn new(addr: SocketAddr, format: PushFormat, transport: Transport, client: &str, timeout: Duration) -> Result<Pusher> {
+        let udp = if transport == Transport::Udp {
+            let bind: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0".parse()? } else { "[::]:0".parse()? };
+            Some(UdpSocket::bind(bind).context("binding push socket")?)
+        } else {
+            None
+        };
+        info!("pushing ticker stats as {:?} over {:?} to {}", format, transport, addr);
+        Ok(Pusher {
+            addr,
+            format,
+            transport,
+            client: client.to_string(),
+            udp,
+            tcp: None,
+            timeout,
+        })
+    }
+
+    /// push one interval - a failure is only logged so the collector being down never stops echos
+    pub fn push(&mut self, target: &str, snap: &StatSnapshot, elapsed: Duration) {
+        let payload = self.render(target, snap, elapsed);
+        if let Err(e) = self.send(payload.as_bytes()) {
+            warn!("unable to push stats to {}: {}", self.addr, crate::single_line_error(&e));
+        }
+    }
+
+    fn send(&mut self, payload: &[u8]) -> Result<()> {
+        match self.transport {
+            Transport::Udp => {
+                let socket = self.udp.as_ref().expect("udp push socket");
+                socket.send_to(payload, self.addr).context("sending push datagram")?;
+            },
+            Transport::Tcp => {
+                if self.tcp.is_none() {
+                    let stream = TcpStream::connect_timeout(&self.addr, self.timeout).context("connecting push stream")?;
+                    stream.set_write_timeout(Some(self.timeout)).context("setting write timeout of push stream")?;
+                    self.tcp = Some(stream);
+                }
+                let stream = self.tcp.as_mut().expect("tcp push stream");
+                if let Err(e) = stream.write_all(payload).and_then(|_| stream.flush()) {
+                    self.tcp = None;
+                    return Err(e).context("writing push stream");
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// the interval as stat name, value and whether it is a count over the interval rather than a level
+    ///
+    /// echo times are only there when some came back
+    fn fields(snap: &StatSnapshot, elapsed: Duration) -> Vec<(&'static str, f64, bool)> {
+        // whole nanoseconds keep float noise like 0.17203100000000002 off the wire
+        let ms = |d: Duration| d.as_nanos() as f64 / 1e6;
+        let mut fields = vec![
+            ("sent", snap.sent as f64, true),
+            ("echos", snap.echos as f64, true),
+            ("lost", snap.lost as f64, true),
+            ("late", snap.late as f64, true),
+            ("conn_failures", snap.conn_failures as f64, true),
+            ("rate", (snap.echos as f64 / elapsed.as_secs_f64() * 1000.0).round() / 1000.0, false),
+            ("loss_pct", snap.loss_pct(), false),
+        ];
+        fields.extend(FAILURE_FIELDS.iter().zip(snap.failure_causes).map(|(name, n)| (*name, n as f64, true)));
+        if let Some(avg) = snap.avg_time() {
+            fields.extend_from_slice(&[
+                ("min_ms", ms(snap.min_time), false),
+                ("avg_ms", ms(avg), false),
+                ("max_ms", ms(snap.max_time), false),
+                ("p50_ms", ms(snap.percentile(0.50)), false),
+                ("p90_ms", ms(snap.percentile(0.90)), false),
+                ("p99_ms", ms(snap.percentile(0.99)), false),
+                ("p999_ms", ms(snap.percentile(0.999)), false),
+            ]);
+        }
+        fields
+    }
+
+    fn render(&self, target: &str, snap: &StatSnapshot, elapsed: Duration) -> String {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("cannot get system time");
+        let fields = Self::fields(snap, elapsed);
+        let mut out = String::new();
+        match self.format {
+            PushFormat::Influx => {
+                let _ = write!(out, "netdelay,client={},target={} ", influx_tag(&self.client), influx_tag(target));
+                let line: Vec<String> = fields.iter().map(|(name, v, count)| if *count {
+                    format!("{}={}i", name, *v as u64)
+                } else {
+                    format!("{}={}", name, v)
+                }).collect();
+                let _ = writeln!(out, "{} {}", line.join(","), now.as_nanos());
+            },
+            PushFormat::Statsd => {
+                for (name, v, count) in fields.iter() {
+                    let kind = if *count { "c" } else { "g" };
+                    let _ = writeln!(out, "netdelay.{}.{}.{}:{}|{}", metric_part(&self.client), metric_part(target), name, v, kind);
+                }
+            },
+            PushFormat::Graphite => {
+                for (name, v, _) in fields.iter() {
+                    let _ = writeln!(out, "netdelay.{}.{}.{} {} {}", metric_part(&self.client), metric_part(target), name, v, now.as_secs());
+                }
+            },
+        }
+        out
+    }
+}
+
+/// escape the characters line protocol gives a meaning to in tag values
+fn influx_tag(s: &str) -> String {
+    s.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+/// one dot separated element of a StatsD or Graphite name - so 10.0.0.1:5150 becomes 10_0_0_1_5150
+fn metric_part(s: &str) -> String {
+    s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use crate::stat::Stat;
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// an interval of 4 echos sent, 3 back at 1, 2 and 3ms, 1 lost and a refused connect
+    fn snap() -> StatSnapshot {
+        let mut stat = Stat::new();
+        for ms in 1..=3 {
+            stat.sent();
+            stat.update(Duration::from_millis(ms));
+        }
+        stat.sent();
+        stat.lost();
+        stat.conn_failure(Cause::Refused);
+        stat.snap_shot()
+    }
+
+    fn udp_collector() -> (UdpSocket, SocketAddr) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        (socket, addr)
+    }
+
+    fn recv(socket: &UdpSocket) -> String {
+        let mut buf = [0u8; 4096];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn influx_line_over_udp() {
+        let (socket, addr) = udp_collector();
+        let mut pusher = Pusher::new(addr, PushFormat::Influx, Transport::Udp, "probe 1", TIMEOUT).unwrap();
+        pusher.push("dc1=10.0.0.1:5150", &snap(), Duration::from_secs(1));
+        let line = recv(&socket);
+        assert_eq!(line.lines().count(), 1);
+        let (series, rest) = line.trim_end().split_once(' ').unwrap();
+        // the space in the client name is escaped so it does not end the tags
+        assert_eq!(series, "netdelay,client=probe\\");
+        let (tags_rest, fields_time) = rest.split_once(' ').unwrap();
+        assert_eq!(tags_rest, "1,target=dc1\\=10.0.0.1:5150");
+        let (fields, time) = fields_time.split_once(' ').unwrap();
+        assert!(time.parse::<u128>().is_ok());
+        let fields: Vec<&str> = fields.split(',').collect();
+        for want in ["sent=4i", "echos=3i", "lost=1i", "conn_failures=1i", "failures_refused=1i", "failures_reset=0i", "rate=3", "loss_pct=25", "min_ms=1", "max_ms=3"] {
+            assert!(fields.contains(&want), "{} missing from {:?}", want, fields);
+        }
+    }
+
+    #[test]
+    fn statsd_counters_and_gauges_over_udp() {
+        let (socket, addr) = udp_collector();
+        let mut pusher = Pusher::new(addr, PushFormat::Statsd, Transport::Udp, "probe", TIMEOUT).unwrap();
+        pusher.push("10.0.0.1:5150", &snap(), Duration::from_secs(1));
+        let lines: Vec<String> = recv(&socket).lines().map(|l| l.to_string()).collect();
+        assert!(lines.contains(&"netdelay.probe.10_0_0_1_5150.sent:4|c".to_string()), "{:?}", lines);
+        assert!(lines.contains(&"netdelay.probe.10_0_0_1_5150.failures_refused:1|c".to_string()), "{:?}", lines);
+        assert!(lines.contains(&"netdelay.probe.10_0_0_1_5150.loss_pct:25|g".to_string()), "{:?}", lines);
+        assert!(lines.contains(&"netdelay.probe.10_0_0_1_5150.max_ms:3|g".to_string()), "{:?}", lines);
+    }
+
+    #[test]
+    fn graphite_lines_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut pusher = Pusher::new(addr, PushFormat::Graphite, Transport::Tcp, "probe", TIMEOUT).unwrap();
+        pusher.push("dc1", &snap(), Duration::from_secs(1));
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let first = lines.next().unwrap().unwrap();
+        let parts: Vec<&str> = first.split(' ').collect();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(&parts[..2], &["netdelay.probe.dc1.sent", "4"]);
+        assert!(parts[2].parse::<u64>().is_ok());
+        let names: Vec<String> = lines.take(6).map(|l| l.unwrap().split(' ').next().unwrap().to_string()).collect();
+        assert_eq!(names[0], "netdelay.probe.dc1.echos");
+        assert_eq!(names[3], "netdelay.probe.dc1.conn_failures");
+    }
+
+    #[test]
+    fn no_echo_times_without_echos() {
+        let mut stat = Stat::new();
+        stat.sent();
+        stat.lost();
+        let fields = Pusher::fields(&stat.snap_shot(), Duration::from_secs(1));
+        assert!(fields.iter().all(|(name, _, _)| !name.ends_with("_ms")));
+    }
+}
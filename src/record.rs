@@ -0,0 +1,220 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use anyhow::{anyhow, Context};
+use bincode::Options;
+use log::warn;
+use serde::{Serialize, Deserialize};
+
+use crate::util;
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// start of every recording file - the last byte is the format version
+const MAGIC: &[u8; 8] = b"NDREC\0\0\x01";
+
+/// unlike the wire format recordings use varints to keep them small
+fn codec() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+/// one raw sample in a --record file - all times are wall-clock nanoseconds since the UNIX epoch
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Sample {
+    /// names the target that samples with this id are about until the id is declared again
+    ///
+    /// written when a client starts so every run appended to a file has its own ids
+    Target { time: u64, id: u32, name: String },
+    Connected { time: u64, target: u32, conn_id: u64 },
+    /// a connection that could not be made or was lost after being made
+    ConnFailure { time: u64, target: u32, conn_id: u64, error: String },
+    /// `rtt` is None when the echo was lost
+    Echo { time: u64, target: u32, conn_id: u64, seq: u64, rtt: Option<u64> },
+}
+
+impl Sample {
+    pub fn time(&self) -> u64 {
+        match self {
+            Sample::Target { time, .. } | Sample::Connected { time, .. }
+            | Sample::ConnFailure { time, .. } | Sample::Echo { time, .. } => *time,
+        }
+    }
+}
+
+/// appends samples to a --record file - cloned handles share it
+#[derive(Clone)]
+pub struct Recorder {
+    out: Arc<Mutex<BufWriter<File>>>,
+    next_id: Arc<AtomicU32>,
+}
+
+impl Recorder {
+    /// new files get the magic header - existing ones must already have it
+    pub fn open(path: &Path) -> Result<Recorder> {
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)
+            .with_context(|| format!("opening record file {}", path.display()))?;
+        if file.metadata().map(|m| m.len() == 0).unwrap_or(true) {
+            file.write_all(MAGIC).context("writing record file header")?;
+        } else {
+            check_magic(&mut file, path)?;
+        }
+        Ok(Recorder {
+            out: Arc::new(Mutex::new(BufWriter::new(file))),
+            next_id: Arc::new(AtomicU32::new(0)),
+        })
+    }
+
+    /// declare `name` and get a handle that records samples about it
+    pub fn for_target(&self, name: &str) -> TargetRecorder {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let rec = TargetRecorder { rec: self.clone(), id };
+        rec.rec.write(&Sample::Target { time: util::unix_nanos(), id, name: name.to_string() });
+        rec
+    }
+
+    /// written through so a crash loses at most the sample being written
+    fn write(&self, sample: &Sample) {
+        let mut out = self.out.lock().expect("Unable to write Recorder at lock");
+        let res = codec().serialize_into(&mut *out, sample).map_err(anyhow::Error::from)
+            .and_then(|_| out.flush().map_err(anyhow::Error::from));
+        if let Err(e) = res {
+            warn!("unable to record sample: {}", e);
+        }
+    }
+}
+
+/// records samples about one target
+#[derive(Clone)]
+pub struct TargetRecorder {
+    rec: Recorder,
+    id: u32,
+}
+
+impl TargetRecorder {
+    pub fn connected(&self, conn_id: u64) {
+        self.rec.write(&Sample::Connected { time: util::unix_nanos(), target: self.id, conn_id });
+    }
+
+    pub fn conn_failure(&self, conn_id: u64, error: &str) {
+        self.rec.write(&Sample::ConnFailure { time: util::unix_nanos(), target: self.id, conn_id, error: error.to_string() });
+    }
+
+    pub fn echo(&self, conn_id: u64, seq: u64, rtt: Option<u64>) {
+        self.rec.write(&Sample::Echo { time: util::unix_nanos(), target: self.id, conn_id, seq, rtt });
+    }
+}
+
+fn check_magic(file: &mut impl Read, path: &Path) -> Result<()> {
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic).with_context(|| format!("reading header of {}", path.display()))?;
+    if &magic != MAGIC {
+        return Err(anyhow!("{} is not a NetDelay recording or is from an unknown version", path.display()));
+    }
+    Ok(())
+}
+
+/// every sample in a recording in the order written - a sample cut short by a crash ends it
+pub struct SampleReader {
+    input: BufReader<File>,
+    path: PathBuf,
+}
+
+impl SampleReader {
+    pub fn open(path: &Path) -> Result<SampleReader> {
+        let mut file = File::open(path).with_context(|| format!("opening record file {}", path.display()))?;
+        check_magic(&mut file, path)?;
+        Ok(SampleReader { input: BufReader::new(file), path: path.to_path_buf() })
+    }
+}
+
+impl Iterator for SampleReader {
+    type Item = Result<Sample>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match codec().deserialize_from(&mut self.input) {
+            Ok(sample) => Some(Ok(sample)),
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(ref io) if io.kind() == ErrorKind::UnexpectedEof => None,
+                _ => Some(Err(anyhow::Error::from(e)).with_context(|| format!("reading sample from {}", self.path.display()))),
+            },
+        }
+    }
+}
+
+/// append exactly `samples`, times and ids as given, to the recording at `path`
+#[cfg(test)]
+pub fn write_samples(path: &Path, samples: &[Sample]) {
+    let rec = Recorder::open(path).unwrap();
+    samples.iter().for_each(|s| rec.write(s));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a path in the temp dir named for the test - removed first so the test starts a new file
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("netdelay-{}-{}.ndr", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn read(path: &Path) -> Vec<Sample> {
+        SampleReader::open(path).unwrap().map(|s| s.unwrap()).collect()
+    }
+
+    #[test]
+    fn samples_read_back_as_written() {
+        let path = temp_path("round-trip");
+        let rec = Recorder::open(&path).unwrap();
+        let a = rec.for_target("a");
+        let b = rec.for_target("b");
+        a.connected(1);
+        a.echo(1, 0, Some(1500));
+        b.conn_failure(1, "refused");
+        a.echo(1, 1, None);
+        drop((rec, a, b));
+        // a second run appended to the file declares its targets again from id 0
+        Recorder::open(&path).unwrap().for_target("b").echo(1, 0, Some(7));
+
+        let samples = read(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(samples.len(), 8);
+        assert!(matches!(&samples[0], Sample::Target { id: 0, name, .. } if name == "a"));
+        assert!(matches!(&samples[1], Sample::Target { id: 1, name, .. } if name == "b"));
+        assert!(matches!(samples[2], Sample::Connected { target: 0, conn_id: 1, .. }));
+        assert!(matches!(samples[3], Sample::Echo { target: 0, conn_id: 1, seq: 0, rtt: Some(1500), .. }));
+        assert!(matches!(&samples[4], Sample::ConnFailure { target: 1, error, .. } if error == "refused"));
+        assert!(matches!(samples[5], Sample::Echo { target: 0, seq: 1, rtt: None, .. }));
+        assert!(matches!(&samples[6], Sample::Target { id: 0, name, .. } if name == "b"));
+        assert!(matches!(samples[7], Sample::Echo { target: 0, rtt: Some(7), .. }));
+        assert!(samples.windows(2).all(|w| w[0].time() <= w[1].time()));
+    }
+
+    #[test]
+    fn sample_cut_off_ends_the_recording() {
+        let path = temp_path("cut-off");
+        write_samples(&path, &[
+            Sample::Target { time: 1, id: 0, name: "a".to_string() },
+            Sample::Echo { time: 2, target: 0, conn_id: 1, seq: 0, rtt: Some(5) },
+            Sample::ConnFailure { time: 3, target: 0, conn_id: 1, error: "connection reset".to_string() },
+        ]);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 4).unwrap();
+        let samples = read(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(samples.iter().map(|s| s.time()).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn not_a_recording() {
+        let path = temp_path("not-a-recording");
+        std::fs::write(&path, b"time,target\n").unwrap();
+        assert!(SampleReader::open(&path).is_err());
+        assert!(Recorder::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,36 @@
+use structopt::StructOpt;
+use anyhow::Context;
+use log::info;
+
+use crate::cli::{ClientOpts, SelftestOpts, ServerOpts};
+use crate::server::EchoServer;
+use crate::sla;
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// echo `opts.count` times to a server on a free loopback port in this same process
+///
+/// any echo lost or not a single one returned fails it with the usual SLA exit codes
+pub fn run(opts: &SelftestOpts) -> Result<i32> {
+    let udp: &[&str] = if opts.udp { &["--udp"] } else { &[] };
+    let server_opts = ServerOpts::from_iter_safe(["server", "127.0.0.1", "--port", "0", "--timeout-socket", "2s"].iter().chain(udp))
+        .context("building selftest server options")?;
+    let server = EchoServer::start(&server_opts)?;
+    let addr = server.local_addr();
+    info!("selftest of {} echos to {}", opts.count, addr);
+
+    let (target, port, count, payload_size) = (addr.to_string(), addr.port().to_string(), opts.count.to_string(), opts.payload_size.to_string());
+    let client_args = ["client", &target, "--port", &port, "--count", &count, "--payload-size", &payload_size
+        , "--max-loss", "0", "--timeout-socket", "2s", "--break-time", "1s"];
+    let client_opts = ClientOpts::from_iter_safe(client_args.iter().chain(udp))
+        .context("building selftest client options")?;
+    let code = crate::run_client(&client_opts);
+    server.stop()?;
+    let code = code?;
+    if code == sla::EXIT_OK {
+        println!("selftest PASS");
+    } else {
+        println!("selftest FAIL");
+    }
+    Ok(code)
+}
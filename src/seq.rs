@@ -0,0 +1,102 @@
+use std::collections::BTreeSet;
+
+/// how far behind the next expected sequence we still remember arrivals for duplicate checks
+const DUP_WINDOW: u64 = 1024;
+
+/// how a reply's sequence number compares to what came back before it
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Arrival {
+    InOrder,
+    /// RFC 4737 - arrived with a sequence lower than the next expected one
+    Reordered,
+    /// this sequence number already came back once
+    Duplicate,
+}
+
+/// per-session tracking of returned sequence numbers
+///
+/// follows RFC 4737 section 3: a reply is reordered when its sequence number is
+/// less than NextExp, otherwise NextExp moves to one past it
+pub struct SeqTracker {
+    next_send: u64,
+    next_exp: u64,
+    seen: BTreeSet<u64>,
+}
+
+impl SeqTracker {
+    pub fn new() -> Self {
+        SeqTracker {
+            next_send: 0,
+            next_exp: 0,
+            seen: BTreeSet::new(),
+        }
+    }
+
+    /// sequence number for the next echo sent
+    pub fn next(&mut self) -> u64 {
+        let seq = self.next_send;
+        self.next_send += 1;
+        seq
+    }
+
+    pub fn arrive(&mut self, seq: u64) -> Arrival {
+        if !self.seen.insert(seq) {
+            return Arrival::Duplicate;
+        }
+        if seq < self.next_exp {
+            Arrival::Reordered
+        } else {
+            self.next_exp = seq + 1;
+            if self.next_exp > DUP_WINDOW {
+                self.seen = self.seen.split_off(&(self.next_exp - DUP_WINDOW));
+            }
+            Arrival::InOrder
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_counts_up_from_zero() {
+        let mut seqs = SeqTracker::new();
+        assert_eq!((seqs.next(), seqs.next(), seqs.next()), (0, 1, 2));
+    }
+
+    #[test]
+    fn lower_than_next_expected_is_reordered() {
+        let mut seqs = SeqTracker::new();
+        assert_eq!(seqs.arrive(0), Arrival::InOrder);
+        // 1 is skipped so NextExp moves past it
+        assert_eq!(seqs.arrive(2), Arrival::InOrder);
+        assert_eq!(seqs.arrive(1), Arrival::Reordered);
+        assert_eq!(seqs.arrive(3), Arrival::InOrder);
+    }
+
+    #[test]
+    fn second_arrival_is_duplicate() {
+        let mut seqs = SeqTracker::new();
+        assert_eq!(seqs.arrive(0), Arrival::InOrder);
+        assert_eq!(seqs.arrive(0), Arrival::Duplicate);
+        assert_eq!(seqs.arrive(2), Arrival::InOrder);
+        assert_eq!(seqs.arrive(1), Arrival::Reordered);
+        assert_eq!(seqs.arrive(1), Arrival::Duplicate);
+        assert_eq!(seqs.arrive(2), Arrival::Duplicate);
+    }
+
+    #[test]
+    fn arrivals_older_than_dup_window_are_forgotten() {
+        let mut seqs = SeqTracker::new();
+        for seq in 0..DUP_WINDOW * 2 {
+            assert_eq!(seqs.arrive(seq), Arrival::InOrder);
+        }
+        assert!(seqs.seen.len() as u64 <= DUP_WINDOW);
+        // still inside the window
+        assert_eq!(seqs.arrive(DUP_WINDOW * 2 - 1), Arrival::Duplicate);
+        assert_eq!(seqs.arrive(DUP_WINDOW), Arrival::Duplicate);
+        // pruned - can no longer be told from a late first arrival
+        assert_eq!(seqs.arrive(0), Arrival::Reordered);
+    }
+}
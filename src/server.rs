@@ -0,0 +1,217 @@
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use anyhow::{anyhow, Context};
+use bincode::Options;
+use log::{debug, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::cli::ServerOpts;
+use crate::dial::{host_port_from_str, HostPort};
+use crate::peers::Peers;
+use crate::{echo_codec, shutdown, single_line_error, udp, util, TimePacket, SHUTDOWN_POLL};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// connections accepted on any listener - numbers them for the peers report
+static SERV_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// a tcp or udp echo server listening on one or more addresses, each on a thread of its own, until `stop`
+pub struct EchoServer {
+    local_addrs: Vec<SocketAddr>,
+    udp: bool,
+    threads: Vec<JoinHandle<Result<()>>>,
+    peers: Peers,
+    timeout_socket: Duration,
+}
+
+impl EchoServer {
+    /// bind to every address in `opts` then serve - port 0 picks a free one
+    pub fn start(opts: &ServerOpts) -> Result<EchoServer> {
+        let mut addrs: Vec<SocketAddr> = vec![];
+        for bind in opts.binds.iter() {
+            let (host, port) = host_port_from_str(bind)?;
+            addrs.extend(HostPort { host, port: port.unwrap_or(opts.port) }.resolve()?.first());
+        }
+        let sockets = if addrs.is_empty() {
+            let any6 = SocketAddr::new(IpAddr::from(Ipv6Addr::UNSPECIFIED), opts.port);
+            match bind(&any6, opts.udp, opts.v6_only) {
+                Ok(socket) => vec![(socket, !opts.v6_only)],
+                Err(e) => {
+                    info!("listening to IPv4 only - no IPv6 here: {}", single_line_error(&e));
+                    vec![(bind(&SocketAddr::new(IpAddr::from(Ipv4Addr::UNSPECIFIED), opts.port), opts.udp, false)?, false)]
+                },
+            }
+        } else {
+            let mut sockets = vec![];
+            for addr in addrs.iter() {
+                // an IPv6 wildcard that also took IPv4 would clash with an IPv4 listener on its port
+                let v6_only = opts.v6_only || addrs.iter().any(|a| a.is_ipv4() && a.port() == addr.port());
+                let dual = addr.is_ipv6() && addr.ip().is_unspecified() && !v6_only;
+                sockets.push((bind(addr, opts.udp, v6_only)?, dual));
+            }
+            sockets
+        };
+
+        let peers = Peers::new();
+        let mut local_addrs = vec![];
+        let mut threads = vec![];
+        for (socket, dual) in sockets {
+            let local_addr = socket.local_addr().ok().and_then(|a| a.as_socket()).context("getting listener address")?;
+            let also = if dual { " and IPv4" } else { "" };
+            let opts_thread = opts.clone();
+            let peers_thread = peers.clone();
+            let thread = if opts.udp {
+                info!("udp server listening to {}{}", &local_addr, also);
+                let socket: UdpSocket = socket.into();
+                std::thread::Builder::new()
+                    .name(format!("udp_server_{}", local_addr))
+                    .spawn(move || udp::server(socket, &opts_thread, &peers_thread))
+                    .context("spawning udp server thread")?
+            } else {
+                info!("server listening to {}{}", &local_addr, also);
+                let listener: TcpListener = socket.into();
+                std::thread::Builder::new()
+                    .name(format!("accept_{}", local_addr))
+                    .spawn(move || {
+                        let res = accept_forever(listener, local_addr, &opts_thread, &peers_thread);
+                        shutdown::request();
+                        res
+                    })
+                    .context("spawning accept thread")?
+            };
+            local_addrs.push(local_addr);
+            threads.push(thread);
+        }
+        Ok(EchoServer {
+            local_addrs,
+            udp: opts.udp,
+            threads,
+            peers,
+            timeout_socket: opts.timeout_socket,
+        })
+    }
+
+    /// the first address listened to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /// request shutdown, wait for every listener to close and log what the server did for each client
+    pub fn stop(self) -> Result<()> {
+        shutdown::request();
+        if !self.udp {
+            // a blocked accept only returns on a connection so make one to each to let them see the shutdown
+            for local_addr in self.local_addrs.iter() {
+                let _ = TcpStream::connect_timeout(&util::wake_addr(local_addr), self.timeout_socket);
+            }
+        }
+        for (thread, local_addr) in self.threads.into_iter().zip(self.local_addrs.iter()) {
+            thread.join().map_err(|_| anyhow!("server thread panicked"))??;
+            info!("server closed listener on {}", local_addr);
+        }
+        self.peers.report();
+        Ok(())
+    }
+}
+
+/// a bound socket for `addr`, listening if tcp - IPv6 ones take IPv4 clients too unless `v6_only`
+fn bind(addr: &SocketAddr, udp: bool, v6_only: bool) -> Result<Socket> {
+    let (ty, protocol) = if udp { (Type::DGRAM, Protocol::UDP) } else { (Type::STREAM, Protocol::TCP) };
+    let socket = Socket::new(Domain::for_address(*addr), ty, Some(protocol)).with_context(|| format!("creating socket for {}", addr))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only).context("setting IPV6_V6ONLY")?;
+    }
+    // as std does - a restarted server can listen again without waiting out TIME_WAIT
+    #[cfg(not(windows))]
+    if !udp {
+        socket.set_reuse_address(true).context("setting SO_REUSEADDR")?;
+    }
+    socket.bind(&(*addr).into()).with_context(|| format!("not a valid IP address: {}", addr))?;
+    if !udp {
+        socket.listen(128).with_context(|| format!("listening on {}", addr))?;
+    }
+    Ok(socket)
+}
+
+/// spawn a thread per connection until shutdown is requested, which drops the listener
+fn accept_forever(listener: TcpListener, local_addr: SocketAddr, opts: &ServerOpts, peers: &Peers) -> Result<()> {
+    for stream in listener.incoming() {
+        if shutdown::requested() {
+            break;
+        }
+        let stream = stream?;
+        let opts = opts.clone();
+        let peers = peers.clone();
+        let id = format!("serv_{}", SERV_COUNT.fetch_add(1, Ordering::Relaxed) + 1);
+        std::thread::Builder::new()
+            .name(id.clone())
+            .spawn(move || {
+                server_thread_handler(stream, local_addr, &opts, &peers, &id);
+            }).unwrap();
+    }
+    Ok(())
+}
+
+fn server_thread_handler(stream: TcpStream, listener: SocketAddr, opts: &ServerOpts, peers: &Peers, id: &str) {
+    if let Err(e) = server(stream, listener, opts, peers, id) {
+        warn!("client thread error: {}", single_line_error(&e));
+    }
+    peers.closed(id);
+}
+
+fn server(mut stream: TcpStream, listener: SocketAddr, opts: &ServerOpts, peers: &Peers, id: &str) -> Result<()> {
+    stream.set_read_timeout(Some(opts.timeout_socket)).context("setting read timeout")?;
+    stream.set_write_timeout(Some(opts.timeout_socket)).context("setting write timeout")?;
+    stream.set_nodelay(true).context("setting nodelay of server socket")?;
+    let client_addr = stream.peer_addr().context("Unable to get peer_address after incoming connection")?;
+    info!("Connection from: {:?} on listener {}", &client_addr, listener);
+
+    loop {
+        let mut tp: TimePacket = echo_codec().deserialize_from(&stream).context(format!("with client IP {} at read", client_addr))?;
+        tp.server_recv = Some(util::unix_nanos());
+        tp.server_send = Some(util::unix_nanos());
+        let out = echo_codec().serialize(&tp).context("serializing reply")?;
+        stream.write_all(&out).context(format!("with client IP {} at write", client_addr))?;
+        stream.flush().context(format!("with client IP {} at flush", client_addr))?;
+        peers.echo(id, &client_addr, out.len());
+        if let Some(ref dur) = opts.interval {
+            std::thread::sleep(*dur);
+        }
+        debug!("Packet sent {:#?}", &tp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use structopt::StructOpt;
+
+    #[test]
+    fn oversized_echo_drops_only_its_connection() {
+        let opts = ServerOpts::from_iter_safe(["server", "127.0.0.1", "--port", "0", "--timeout-socket", "5s"].iter()).unwrap();
+        let server = EchoServer::start(&opts).unwrap();
+        let mut good = TcpStream::connect(server.local_addr()).unwrap();
+        let mut bad = TcpStream::connect(server.local_addr()).unwrap();
+        bad.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // seq, client_send, three None tags then a pad said to be 2^62 bytes long
+        let mut msg = vec![0u8; 16];
+        msg.extend([0u8; 3]);
+        msg.extend((1u64 << 62).to_le_bytes());
+        bad.write_all(&msg).unwrap();
+        match bad.read(&mut [0u8; 16]) {
+            Ok(0) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => {},
+            other => panic!("connection not dropped: {:?}", other),
+        }
+
+        good.write_all(&echo_codec().serialize(&TimePacket::new(7, 8)).unwrap()).unwrap();
+        let tp: TimePacket = echo_codec().deserialize_from(&good).unwrap();
+        assert_eq!((tp.seq, tp.pad.len()), (7, 8));
+        assert!(tp.server_recv.is_some());
+    }
+}
@@ -0,0 +1,91 @@
+use std::time::Duration;
+use chrono::{SecondsFormat, Utc};
+use log::warn;
+use serde::Serialize;
+
+use crate::outage::{self, Outage};
+use crate::record::TargetRecorder;
+use crate::sink::{Record, Sink, csv_field};
+
+/// one line of --echo-out
+#[derive(Serialize)]
+struct EchoRecord<'a> {
+    /// RFC 3339 UTC time the reply came back or the echo was given up on
+    timestamp: String,
+    target: &'a str,
+    conn_id: u64,
+    seq: u64,
+    /// empty when the echo was lost
+    rtt_ns: Option<u64>,
+}
+
+impl Record for EchoRecord<'_> {
+    fn csv_header() -> &'static str {
+        "timestamp,target,conn_id,seq,rtt_ns"
+    }
+
+    fn csv_row(&self) -> String {
+        format!("{},{},{},{},{}", self.timestamp, csv_field(self.target), self.conn_id, self.seq
+                , self.rtt_ns.map(|r| r.to_string()).unwrap_or_default())
+    }
+}
+
+/// per connection context handed to the echo loops - who they talk to and where per echo output goes
+#[derive(Clone)]
+pub struct Session {
+    pub target: String,
+    /// counts up from 1 with every connection the client makes to the target
+    pub conn_id: u64,
+    pub echo_out: Option<Sink>,
+    pub outage_out: Option<Sink>,
+    pub record: Option<TargetRecorder>,
+}
+
+impl Session {
+    pub fn new(target: &str, conn_id: u64, echo_out: Option<Sink>, outage_out: Option<Sink>, record: Option<TargetRecorder>) -> Self {
+        Session {
+            target: target.to_string(),
+            conn_id,
+            echo_out,
+            outage_out,
+            record,
+        }
+    }
+
+    pub fn connected(&self) {
+        if let Some(ref record) = self.record {
+            record.connected(self.conn_id);
+        }
+    }
+
+    /// the connection could not be made or was lost after being made
+    pub fn conn_failure(&self, error: &str) {
+        if let Some(ref record) = self.record {
+            record.conn_failure(self.conn_id, error);
+        }
+    }
+
+    /// the first echo back after `outage` ended it
+    pub fn outage(&self, outage: &Outage) {
+        outage::report(&self.target, outage, self.outage_out.as_ref());
+    }
+
+    /// one echo done - `rtt` is None when it was lost
+    pub fn echo(&self, seq: u64, rtt: Option<Duration>) {
+        if let Some(ref record) = self.record {
+            record.echo(self.conn_id, seq, rtt.map(|r| r.as_nanos() as u64));
+        }
+        if let Some(ref sink) = self.echo_out {
+            let rec = EchoRecord {
+                timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true),
+                target: &self.target,
+                conn_id: self.conn_id,
+                seq,
+                rtt_ns: rtt.map(|r| r.as_nanos() as u64),
+            };
+            if let Err(e) = sink.write(&rec) {
+                warn!("unable to write echo record: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+use log::warn;
+
+use crate::COND_STOP;
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// exit code when a second signal cuts a graceful shutdown short - 128 + SIGINT like a shell
+const EXIT_FORCED: i32 = 130;
+
+lazy_static! {
+    static ref SHUTDOWN: AtomicBool = AtomicBool::new(false);
+}
+
+/// on SIGINT/SIGTERM ask everything to wind down - a second signal exits right away
+pub fn install() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if SHUTDOWN.swap(true, Ordering::SeqCst) {
+            eprintln!("second termination signal - exiting without waiting");
+            std::process::exit(EXIT_FORCED);
+        }
+        warn!("termination signal received - shutting down, signal again to exit immediately");
+        wake();
+    })?;
+    Ok(())
+}
+
+/// shut down as if signalled - for a part that cannot go on on its own
+pub fn request() {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+    wake();
+}
+
+/// wake anything sleeping in wait() - the ticker ignores this until it is told to stop
+fn wake() {
+    let _lock = COND_STOP.0.lock().unwrap();
+    COND_STOP.1.notify_all();
+}
+
+pub fn requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// sleep for `dur` or until shutdown is requested - true if it was
+pub fn wait(dur: Duration) -> bool {
+    let deadline = Instant::now() + dur;
+    let mut lock = COND_STOP.0.lock().unwrap();
+    loop {
+        if requested() {
+            return true;
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return false;
+        }
+        lock = COND_STOP.1.wait_timeout(lock, remaining).unwrap().0;
+    }
+}
@@ -0,0 +1,89 @@
+use std::fs::OpenOptions;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, Context};
+use serde::Serialize;
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// one JSON object per line
+    Jsonl,
+    /// header line then one comma separated row per record
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "jsonl" | "json" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            _ => Err(anyhow!("output format must be one of jsonl, json or csv but got {}", s)),
+        }
+    }
+}
+
+/// a record that can go to a `Sink` in either format
+pub trait Record: Serialize {
+    fn csv_header() -> &'static str;
+    fn csv_row(&self) -> String;
+}
+
+/// quote a csv field only when it has to be
+pub fn csv_field(s: &str) -> String {
+    if s.contains(',') || s.contains('"') || s.contains('\n') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// machine readable output to a file or stdout, independent of the log4rs configuration
+///
+/// cloned handles share the one writer so several threads can write whole lines to it
+#[derive(Clone)]
+pub struct Sink {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+    format: Format,
+    /// csv header still to be written before the first row
+    need_header: Arc<Mutex<bool>>,
+}
+
+impl Sink {
+    /// `-` is stdout - files are appended to and only get a csv header when empty
+    pub fn open(path: &Path, format: Format) -> Result<Sink> {
+        let (out, empty): (Box<dyn Write + Send>, bool) = if path == Path::new("-") {
+            (Box::new(LineWriter::new(std::io::stdout())), true)
+        } else {
+            let file = OpenOptions::new().create(true).append(true).open(path)
+                .with_context(|| format!("opening output file {}", path.display()))?;
+            let empty = file.metadata().map(|m| m.len() == 0).unwrap_or(true);
+            (Box::new(LineWriter::new(file)), empty)
+        };
+        Ok(Sink {
+            out: Arc::new(Mutex::new(out)),
+            format,
+            need_header: Arc::new(Mutex::new(format == Format::Csv && empty)),
+        })
+    }
+
+    pub fn write<R: Record>(&self, rec: &R) -> Result<()> {
+        let line = match self.format {
+            Format::Jsonl => serde_json::to_string(rec).context("serializing output record")?,
+            Format::Csv => rec.csv_row(),
+        };
+        let mut out = self.out.lock().expect("Unable to write Sink at lock");
+        let mut need_header = self.need_header.lock().expect("Unable to write Sink at lock");
+        if *need_header {
+            writeln!(out, "{}", R::csv_header()).context("writing output header")?;
+            *need_header = false;
+        }
+        writeln!(out, "{}", line).context("writing output record")?;
+        Ok(())
+    }
+}
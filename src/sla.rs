@@ -0,0 +1,103 @@
+use crate::cli::ClientOpts;
+use crate::stat::StatSnapshot;
+
+/// exit codes of a finished client run - violations are OR'd together so a
+/// run that broke both p99 and loss exits with 6
+pub const EXIT_OK: i32 = 0;
+/// any error that stopped NetDelay from running at all - what main() always returned
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_P99: i32 = 2;
+pub const EXIT_LOSS: i32 = 4;
+pub const EXIT_OUTAGE: i32 = 8;
+/// criteria were given but not a single echo came back to judge them on
+pub const EXIT_NO_ECHOS: i32 = 16;
+
+fn has_criteria(cli: &ClientOpts) -> bool {
+    cli.max_p99.is_some() || cli.max_loss.is_some() || cli.max_outage.is_some()
+}
+
+/// judge a whole run against the --max-* criteria and print each one broken
+pub fn check(cli: &ClientOpts, target: &str, snap: &StatSnapshot) -> i32 {
+    if !has_criteria(cli) {
+        return EXIT_OK;
+    }
+    let mut code = EXIT_OK;
+    if snap.echos == 0 {
+        report!("SLA FAIL {}: no echos returned", target);
+        code |= EXIT_NO_ECHOS;
+    }
+    if let Some(max_p99) = cli.max_p99 {
+        let p99 = snap.percentile(0.99);
+        if snap.echos > 0 && p99 > max_p99 {
+            report!("SLA FAIL {}: p99 {:?} over max {:?}", target, p99, max_p99);
+            code |= EXIT_P99;
+        }
+    }
+    if let Some(max_loss) = cli.max_loss {
+        if snap.loss_pct() > max_loss {
+            report!("SLA FAIL {}: loss {:.3}% over max {:.3}%", target, snap.loss_pct(), max_loss);
+            code |= EXIT_LOSS;
+        }
+    }
+    if let Some(max_outage) = cli.max_outage {
+        if snap.max_outage > max_outage {
+            report!("SLA FAIL {}: outage of {:?} over max {:?}", target, snap.max_outage, max_outage);
+            code |= EXIT_OUTAGE;
+        }
+    }
+    if code == EXIT_OK {
+        report!("SLA PASS {}", target);
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use structopt::StructOpt;
+    use super::*;
+
+    fn opts(criteria: &[&str]) -> ClientOpts {
+        ClientOpts::from_iter_safe(["client", "127.0.0.1"].iter().chain(criteria)).unwrap()
+    }
+
+    /// `echos` of 1ms but the first of 50ms, `lost` lost and a longest outage of `outage_ms`
+    fn snap(echos: u64, lost: u64, outage_ms: u64) -> StatSnapshot {
+        let mut snap = StatSnapshot::new();
+        snap.sent = echos + lost;
+        for i in 0..echos {
+            snap.update(Duration::from_millis(if i == 0 { 50 } else { 1 }), None);
+        }
+        snap.lost = lost;
+        snap.max_outage = Duration::from_millis(outage_ms);
+        snap
+    }
+
+    #[test]
+    fn no_criteria_always_passes() {
+        assert_eq!(check(&opts(&[]), "t", &snap(0, 100, 60_000)), EXIT_OK);
+    }
+
+    #[test]
+    fn within_criteria_passes() {
+        let cli = opts(&["--max-p99", "60ms", "--max-loss", "1", "--max-outage", "2s"]);
+        assert_eq!(check(&cli, "t", &snap(99, 1, 2000)), EXIT_OK);
+    }
+
+    #[test]
+    fn each_criterion_broken_sets_its_bit() {
+        let cli = opts(&["--max-p99", "10ms", "--max-loss", "0.5", "--max-outage", "1s"]);
+        // p99 of 2 echos where one is 50ms is 50ms
+        assert_eq!(check(&cli, "t", &snap(2, 0, 0)), EXIT_P99);
+        assert_eq!(check(&cli, "t", &snap(200, 2, 0)), EXIT_LOSS);
+        assert_eq!(check(&cli, "t", &snap(2, 1, 1001)), EXIT_P99 | EXIT_LOSS | EXIT_OUTAGE);
+    }
+
+    #[test]
+    fn no_echos_skips_p99_but_still_judges_loss_and_outage() {
+        let cli = opts(&["--max-p99", "1ms"]);
+        assert_eq!(check(&cli, "t", &snap(0, 0, 0)), EXIT_NO_ECHOS);
+        let cli = opts(&["--max-p99", "1ms", "--max-loss", "50", "--max-outage", "1s"]);
+        assert_eq!(check(&cli, "t", &snap(0, 100, 5000)), EXIT_NO_ECHOS | EXIT_LOSS | EXIT_OUTAGE);
+    }
+}
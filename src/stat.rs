@@ -0,0 +1,433 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use hdrhistogram::Histogram;
+
+use crate::seq::Arrival;
+use crate::clock::{OffsetEstimator, ClockOffset};
+use crate::jitter::Jitter;
+use crate::outage::{Cause, Outage};
+
+/// echo times are recorded in nanoseconds - anything over an hour is clamped
+const HIST_MAX_NANOS: u64 = 3_600_000_000_000;
+const HIST_SIGFIG: u8 = 3;
+
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, HIST_MAX_NANOS, HIST_SIGFIG).expect("Unable to create echo time histogram")
+}
+
+struct _Stat {
+    /// reset by every snap_shot
+    interval: StatSnapshot,
+    /// since the Stat was created
+    total: StatSnapshot,
+    /// smoothed so it spans ticker intervals
+    jitter: Jitter,
+    /// spans ticker intervals - only there when clock offset estimation is on
+    clock: Option<OffsetEstimator>,
+    /// from the first failure since the last echo that came back - `duration` is filled in when it ends
+    outage: Option<(Instant, Outage)>,
+    /// availability is the part of the time since this not spent in outages
+    started: Instant,
+}
+
+/// min/avg/max of a delay that can go negative - one-way delays between unsynchronised clocks
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SignedDelay {
+    pub count: u64,
+    pub tot_nanos: i64,
+    pub max_nanos: i64,
+    pub min_nanos: i64,
+}
+
+impl SignedDelay {
+    pub fn add(&mut self, nanos: i64) {
+        if self.count == 0 {
+            self.max_nanos = nanos;
+            self.min_nanos = nanos;
+        } else {
+            self.max_nanos = self.max_nanos.max(nanos);
+            self.min_nanos = self.min_nanos.min(nanos);
+        }
+        self.count += 1;
+        self.tot_nanos += nanos;
+    }
+
+    pub fn avg_nanos(&self) -> Option<i64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.tot_nanos / self.count as i64)
+        }
+    }
+}
+
+/// echo stats over a span of time - one ticker interval as taken by `Stat::snap_shot`
+/// or the whole run as taken by `Stat::summary`
+#[derive(Clone)]
+pub struct StatSnapshot {
+    /// echos sent whether they came back or not
+    pub sent: u64,
+    pub echos: u64,
+    pub tot_time: Duration,
+    pub max_time: Duration,
+    pub min_time: Duration,
+    /// every echo time of the interval in nanoseconds
+    pub hist: Histogram<u64>,
+    pub lost: u64,
+    pub late: u64,
+    pub dup: u64,
+    pub reordered: u64,
+    pub fwd: SignedDelay,
+    pub ret: SignedDelay,
+    pub offset: Option<ClockOffset>,
+    /// RFC 3550 smoothed jitter of the echo time as of the end of the interval
+    pub jitter_nanos: i64,
+    /// RFC 3393 delay variation between consecutive echo times in the interval
+    pub ipdv: SignedDelay,
+    pub connects: u64,
+    /// failed connection attempts plus connections lost after being made
+    pub conn_failures: u64,
+    /// conn_failures split by what went wrong - in the order of Cause::FAILURES
+    pub failure_causes: [u64; Cause::FAILURES.len()],
+    /// longest time from a failure to the next echo that came back
+    pub max_outage: Duration,
+    /// outages, the one going on now included, and the time of those that ended all told
+    pub outages: u64,
+    pub outage_time: Duration,
+    /// percent of the time since the Stat was created not spent in an outage - for the whole run even in an interval
+    pub availability_pct: f64,
+}
+
+impl StatSnapshot {
+    pub fn new() -> Self {
+        StatSnapshot {
+            sent: 0,
+            echos: 0,
+            tot_time: Duration::from_secs(0),
+            max_time: Duration::from_secs(0),
+            min_time: Duration::from_millis(u64::MAX),
+            hist: new_histogram(),
+            lost: 0,
+            late: 0,
+            dup: 0,
+            reordered: 0,
+            fwd: SignedDelay::default(),
+            ret: SignedDelay::default(),
+            offset: None,
+            jitter_nanos: 0,
+            ipdv: SignedDelay::default(),
+            connects: 0,
+            conn_failures: 0,
+            failure_causes: [0; Cause::FAILURES.len()],
+            max_outage: Duration::from_secs(0),
+            outages: 0,
+            outage_time: Duration::from_secs(0),
+            availability_pct: 100.0,
+        }
+    }
+
+    pub fn update(&mut self, time: Duration, ipdv: Option<i64>) {
+        self.echos += 1;
+        self.tot_time += time;
+        self.max_time = self.max_time.max(time);
+        self.min_time = self.min_time.min(time);
+        self.hist.saturating_record(time.as_nanos().min(HIST_MAX_NANOS as u128) as u64);
+        if let Some(ipdv) = ipdv {
+            self.ipdv.add(ipdv);
+        }
+    }
+
+    /// echo time at quantile `q` of 0.0 to 1.0
+    pub fn percentile(&self, q: f64) -> Duration {
+        Duration::from_nanos(self.hist.value_at_quantile(q))
+    }
+
+    pub fn stddev(&self) -> Duration {
+        Duration::from_nanos(self.hist.stdev() as u64)
+    }
+
+    pub fn avg_time(&self) -> Option<Duration> {
+        if self.echos == 0 {
+            None
+        } else {
+            Some(Duration::from_nanos((self.tot_time.as_nanos() / self.echos as u128) as u64))
+        }
+    }
+
+    /// percent of echos sent that never came back
+    pub fn loss_pct(&self) -> f64 {
+        if self.sent == 0 {
+            0.0
+        } else {
+            self.lost as f64 * 100.0 / self.sent as f64
+        }
+    }
+
+    /// connection failures put down to `cause`
+    pub fn failures(&self, cause: Cause) -> u64 {
+        Cause::FAILURES.iter().position(|c| *c == cause).map(|i| self.failure_causes[i]).unwrap_or(0)
+    }
+
+    /// the causes connections failed for and how often, leaving out those that never happened
+    pub fn failures_by_cause(&self) -> Vec<(Cause, u64)> {
+        Cause::FAILURES.iter().zip(self.failure_causes).filter(|(_, n)| *n > 0).map(|(c, n)| (*c, n)).collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct Stat {
+    inner: Arc<Mutex<_Stat>>,
+}
+
+impl _Stat {
+    fn both(&mut self) -> [&mut StatSnapshot; 2] {
+        [&mut self.interval, &mut self.total]
+    }
+
+    fn finish(&self, mut snap: StatSnapshot) -> StatSnapshot {
+        snap.offset = self.clock.as_ref().and_then(|c| c.estimate());
+        snap.jitter_nanos = self.jitter.jitter_nanos();
+        // an outage still going on counts for as long as it has lasted so far
+        let ongoing = self.outage.as_ref().map(|o| o.0.elapsed()).unwrap_or_default();
+        snap.max_outage = snap.max_outage.max(ongoing);
+        if self.outage.is_some() {
+            snap.outages += 1;
+        }
+        let run = self.started.elapsed().as_secs_f64();
+        if run > 0.0 {
+            let down = (self.total.outage_time + ongoing).as_secs_f64();
+            snap.availability_pct = (100.0 * (1.0 - down / run)).clamp(0.0, 100.0);
+        }
+        snap
+    }
+
+    /// the outage this failure starts or is part of
+    fn failure(&mut self, cause: Cause) -> &mut Outage {
+        self.jitter.gap();
+        &mut self.outage.get_or_insert_with(|| (Instant::now(), Outage {
+            start: Utc::now(),
+            duration: Duration::from_secs(0),
+            cause,
+            conn_failures: 0,
+            lost: 0,
+            ongoing: true,
+        })).1
+    }
+}
+
+impl Stat {
+    pub fn new() -> Self {
+        Stat {
+            inner: Arc::new(Mutex::new(_Stat {
+                interval: StatSnapshot::new(),
+                total: StatSnapshot::new(),
+                jitter: Jitter::default(),
+                clock: None,
+                outage: None,
+                started: Instant::now(),
+            }))
+        }
+    }
+
+    /// estimate the server clock offset over the last `window` echos and correct one-way delays with it
+    pub fn with_clock_offset(self, window: usize) -> Self {
+        self.inner.lock().expect("Unable to update State at lock").clock = Some(OffsetEstimator::new(window));
+        self
+    }
+
+    pub fn sent(&mut self) {
+        let mut lock = self.inner.lock().expect("Unable to update State at lock");
+        lock.both().iter_mut().for_each(|s| s.sent += 1);
+    }
+
+    /// an echo came back - returns the outage it ended if there was one
+    pub fn update(&mut self, time_ms: Duration) -> Option<Outage> {
+        let mut lock = self.inner.lock().expect("Unable to update State at lock");
+        let ipdv = lock.jitter.add(time_ms.as_nanos() as i64);
+        lock.both().iter_mut().for_each(|s| s.update(time_ms, ipdv));
+        let (start, mut outage) = lock.outage.take()?;
+        outage.duration = start.elapsed();
+        outage.ongoing = false;
+        lock.both().iter_mut().for_each(|s| {
+            s.max_outage = s.max_outage.max(outage.duration);
+            s.outages += 1;
+            s.outage_time += outage.duration;
+        });
+        Some(outage)
+    }
+
+    /// the outage going on now, as long as it has lasted so far
+    pub fn ongoing_outage(&self) -> Option<Outage> {
+        let lock = self.inner.lock().expect("Unable to read State at lock");
+        lock.outage.as_ref().map(|(start, outage)| Outage { duration: start.elapsed(), ..outage.clone() })
+    }
+
+    /// forward and return one-way delays from the four echo timestamps - t1 client send,
+    /// t2 server receive, t3 server send and t4 client receive
+    ///
+    /// returns the delays recorded in nanoseconds, corrected by the clock offset if that is estimated
+    pub fn update_one_way(&mut self, t1: u64, t2: u64, t3: u64, t4: u64) -> (i64, i64) {
+        let mut lock = self.inner.lock().expect("Unable to update State at lock");
+        let mut fwd_nanos = t2 as i64 - t1 as i64;
+        let mut ret_nanos = t4 as i64 - t3 as i64;
+        if let Some(ref mut clock) = lock.clock {
+            clock.add(t1, t2, t3, t4);
+            if let Some(off) = clock.estimate() {
+                fwd_nanos -= off.offset_nanos;
+                ret_nanos += off.offset_nanos;
+            }
+        }
+        lock.both().iter_mut().for_each(|s| {
+            s.fwd.add(fwd_nanos);
+            s.ret.add(ret_nanos);
+        });
+        (fwd_nanos, ret_nanos)
+    }
+
+    /// udp echo that never came back within the socket timeout
+    pub fn lost(&mut self) {
+        self.lost_to(Cause::Loss);
+    }
+
+    /// echo that never came back because of `cause` - an outage it starts is put down to that
+    pub fn lost_to(&mut self, cause: Cause) {
+        let mut lock = self.inner.lock().expect("Unable to update State at lock");
+        lock.both().iter_mut().for_each(|s| s.lost += 1);
+        lock.failure(cause).lost += 1;
+    }
+
+    /// reply that showed up after its echo was already counted as lost
+    pub fn late(&mut self) {
+        let mut lock = self.inner.lock().expect("Unable to update State at lock");
+        lock.both().iter_mut().for_each(|s| s.late += 1);
+    }
+
+    /// count a reply that was a duplicate or arrived out of order
+    pub fn arrival(&mut self, arrival: Arrival) {
+        let mut lock = self.inner.lock().expect("Unable to update State at lock");
+        lock.both().iter_mut().for_each(|s| match arrival {
+            Arrival::InOrder => {},
+            Arrival::Reordered => s.reordered += 1,
+            Arrival::Duplicate => s.dup += 1,
+        });
+    }
+
+    pub fn connected(&mut self) {
+        let mut lock = self.inner.lock().expect("Unable to update State at lock");
+        lock.both().iter_mut().for_each(|s| s.connects += 1);
+    }
+
+    pub fn conn_failure(&mut self, cause: Cause) {
+        let mut lock = self.inner.lock().expect("Unable to update State at lock");
+        let i = Cause::FAILURES.iter().position(|c| *c == cause);
+        lock.both().iter_mut().for_each(|s| {
+            s.conn_failures += 1;
+            if let Some(i) = i {
+                s.failure_causes[i] += 1;
+            }
+        });
+        lock.failure(cause).conn_failures += 1;
+    }
+
+    pub fn snap_shot(&mut self) -> StatSnapshot {
+        let mut lock = self.inner.lock().expect("Unable to take snap_shot of Stat at lock");
+        let snap = std::mem::replace(&mut lock.interval, StatSnapshot::new());
+        lock.finish(snap)
+    }
+
+    /// everything since the Stat was created - not reset
+    pub fn summary(&self) -> StatSnapshot {
+        let lock = self.inner.lock().expect("Unable to take summary of Stat at lock");
+        lock.finish(lock.total.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// histogram values come back to within HIST_SIGFIG significant digits
+    fn about(d: Duration, nanos: u64) -> bool {
+        d.as_nanos().abs_diff(nanos as u128) <= nanos as u128 / 1000
+    }
+
+    #[test]
+    fn percentiles_and_stddev() {
+        let mut snap = StatSnapshot::new();
+        for ms in 1..=100 {
+            snap.update(Duration::from_millis(ms), None);
+        }
+        for (q, ms) in [(0.0, 1), (0.5, 50), (0.9, 90), (0.99, 99), (1.0, 100)] {
+            assert!(about(snap.percentile(q), ms * 1_000_000), "p{} is {:?}", q, snap.percentile(q));
+        }
+        assert_eq!(snap.avg_time(), Some(Duration::from_micros(50_500)));
+
+        let mut snap = StatSnapshot::new();
+        for ms in [10, 20, 10, 20] {
+            snap.update(Duration::from_millis(ms), None);
+        }
+        assert!(about(snap.stddev(), 5_000_000), "stddev is {:?}", snap.stddev());
+    }
+
+    #[test]
+    fn echo_over_an_hour_is_clamped_in_the_histogram_only() {
+        let mut snap = StatSnapshot::new();
+        snap.update(Duration::from_secs(7200), None);
+        assert!(about(snap.percentile(1.0), HIST_MAX_NANOS));
+        assert_eq!(snap.max_time, Duration::from_secs(7200));
+        assert_eq!(snap.hist.len(), 1);
+    }
+
+    #[test]
+    fn snap_shot_resets_the_interval_but_not_the_summary() {
+        let mut stat = Stat::new();
+        stat.sent();
+        stat.update(Duration::from_millis(10));
+        stat.sent();
+        stat.lost();
+        let first = stat.snap_shot();
+        assert_eq!((first.sent, first.echos, first.lost, first.hist.len()), (2, 1, 1, 1));
+
+        stat.sent();
+        stat.update(Duration::from_millis(30));
+        let second = stat.snap_shot();
+        assert_eq!((second.sent, second.echos, second.lost, second.hist.len()), (1, 1, 0, 1));
+        assert_eq!((second.min_time, second.max_time), (Duration::from_millis(30), Duration::from_millis(30)));
+
+        let empty = stat.snap_shot();
+        assert_eq!((empty.sent, empty.echos, empty.hist.len(), empty.avg_time()), (0, 0, 0, None));
+
+        let total = stat.summary();
+        assert_eq!((total.sent, total.echos, total.lost, total.hist.len()), (3, 2, 1, 2));
+        assert_eq!((total.min_time, total.max_time), (Duration::from_millis(10), Duration::from_millis(30)));
+        assert_eq!(stat.summary().echos, 2);
+    }
+
+    #[test]
+    fn ongoing_outage_is_counted_once() {
+        let mut stat = Stat::new();
+        stat.update(Duration::from_millis(1));
+        assert_eq!(stat.summary().outages, 0);
+        stat.conn_failure(Cause::Refused);
+        stat.lost();
+        assert_eq!(stat.snap_shot().outages, 1);
+        assert_eq!(stat.summary().outages, 1);
+        assert!(stat.update(Duration::from_millis(1)).is_some());
+        assert_eq!(stat.snap_shot().outages, 1);
+        assert_eq!(stat.summary().outages, 1);
+        stat.update(Duration::from_millis(1));
+        assert_eq!(stat.snap_shot().outages, 0);
+        assert_eq!(stat.summary().outages, 1);
+    }
+
+    #[test]
+    fn outage_cause_is_the_first_failure() {
+        let mut stat = Stat::new();
+        stat.lost_to(Cause::Timeout);
+        stat.conn_failure(Cause::Refused);
+        let outage = stat.ongoing_outage().unwrap();
+        assert_eq!((outage.cause, outage.lost, outage.conn_failures), (Cause::Timeout, 1, 1));
+        assert_eq!(stat.summary().failures_by_cause(), vec![(Cause::Refused, 1)]);
+    }
+}
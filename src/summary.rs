@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+use crate::stat::StatSnapshot;
+use crate::duration_to_human;
+
+/// end of run report in the spirit of ping's closing statistics
+pub fn print(human_time: bool, target: &str, snap: &StatSnapshot, run_time: Duration) {
+    let fmt = |d: Duration| if human_time {
+        duration_to_human(&d, 2)
+    } else {
+        format!("{:.3}ms", d.as_secs_f64() * 1000f64)
+    };
+    report!("--- {} NetDelay summary ---", target);
+    report!("run time: {}  echos sent: {}  returned: {}  lost: {} ({:.3}%)  late: {}  dup: {}  reord: {}"
+             , duration_to_human(&run_time, 2), snap.sent, snap.echos, snap.lost, snap.loss_pct()
+             , snap.late, snap.dup, snap.reordered);
+    if let Some(avg) = snap.avg_time() {
+        report!("min/avg/max: {}/{}/{}  p50: {}  p90: {}  p99: {}  p99.9: {}  stddev: {}"
+                 , fmt(snap.min_time), fmt(avg), fmt(snap.max_time)
+                 , fmt(snap.percentile(0.50)), fmt(snap.percentile(0.90)), fmt(snap.percentile(0.99))
+                 , fmt(snap.percentile(0.999)), fmt(snap.stddev()));
+    } else {
+        report!("no echos returned");
+    }
+    let outage = if snap.max_outage == Duration::from_secs(0) {
+        "none".to_string()
+    } else {
+        duration_to_human(&snap.max_outage, 2)
+    };
+    report!("connects: {}  connection failures: {}  outages: {}  longest outage: {}  availability: {:.3}%"
+             , snap.connects, snap.conn_failures, snap.outages, outage, snap.availability_pct);
+    let causes = snap.failures_by_cause();
+    if !causes.is_empty() {
+        let causes: Vec<String> = causes.iter().map(|(cause, n)| format!("{}: {}", cause, n)).collect();
+        report!("connection failures by cause - {}", causes.join("  "));
+    }
+}
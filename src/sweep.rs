@@ -0,0 +1,63 @@
+use std::time::Duration;
+use anyhow::Context;
+use log::{info, warn};
+
+use crate::cli::ClientOpts;
+use crate::dial::HostPort;
+use crate::stat::{Stat, StatSnapshot};
+use crate::limit::Limit;
+use crate::session::Session;
+use crate::sink::Sink;
+use crate::{udp, duration_to_human};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// payload sizes doubling from `min` and ending exactly on `max`
+pub fn sweep_sizes(min: usize, max: usize) -> Vec<usize> {
+    let mut sizes = vec![];
+    let mut size = min;
+    while size < max {
+        sizes.push(size);
+        size = if size == 0 { 1 } else { size * 2 };
+    }
+    sizes.push(max);
+    sizes
+}
+
+/// echo `cli.sweep_count` times at each payload size over a fresh connection and
+/// print a table of latency against size
+pub fn run(cli: &ClientOpts, target: &str, dest: &HostPort, (min, max): (usize, usize), echo_out: Option<Sink>) -> Result<()> {
+    let mut rows: Vec<(usize, StatSnapshot)> = vec![];
+    for (conn_id, size) in sweep_sizes(min, max).into_iter().enumerate() {
+        if cli.udp && udp::check_payload_size(size).is_err() {
+            warn!("skipping payload size {} - too large for a udp datagram", size);
+            continue;
+        }
+        info!("sweep of {} echos with payload size {}", cli.sweep_count, size);
+        let mut stat = Stat::new();
+        let mut limit = Limit::new(Some(cli.sweep_count), None);
+        let session = Session::new(target, conn_id as u64 + 1, echo_out.clone(), None, None);
+        if cli.udp {
+            let (socket, _) = udp::build_client_socket(cli, &dest.resolve()?, cli.timeout_socket)?;
+            udp::client(socket, cli, dest, stat.clone(), size, &mut limit, &session)
+        } else {
+            let (stream, _) = crate::build_client_stream(cli, &dest.resolve()?, cli.timeout_socket)?;
+            crate::client(stream, cli, stat.clone(), size, &mut limit, &session)
+        }.with_context(|| format!("sweep at payload size {}", size))?;
+        rows.push((size, stat.snap_shot()));
+    }
+
+    let fmt = |d: Duration| if cli.human_time {
+        duration_to_human(&d, 2)
+    } else {
+        format!("{:.3}ms", d.as_secs_f64() * 1000f64)
+    };
+    report!("{:>10} {:>7} {:>6} {:>12} {:>12} {:>12} {:>12} {:>12}", "size", "echos", "lost", "min", "avg", "p50", "p99", "max");
+    for (size, snap) in rows.iter() {
+        let avg = snap.avg_time().unwrap_or_default();
+        let min_time = if snap.echos > 0 { snap.min_time } else { Duration::from_secs(0) };
+        report!("{:>10} {:>7} {:>6} {:>12} {:>12} {:>12} {:>12} {:>12}", size, snap.echos, snap.lost
+                 , fmt(min_time), fmt(avg), fmt(snap.percentile(0.50)), fmt(snap.percentile(0.99)), fmt(snap.max_time));
+    }
+    Ok(())
+}
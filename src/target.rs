@@ -0,0 +1,123 @@
+use std::str::FromStr;
+use std::time::Duration;
+use anyhow::anyhow;
+
+use crate::cli::{dur_from_str, ClientOpts};
+use crate::dial::{host_port_from_str, HostPort};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// a server to echo to given as `[name=]host[:port][,key=value...]`
+///
+/// the host is looked up again on every connect so a DNS change is followed.  Keys override the client options of the same name for this target only - interval,
+/// info-threshold and warn-threshold
+#[derive(Debug, Clone)]
+pub struct TargetSpec {
+    /// what the target is called in logs, records and metrics - host:port when not given
+    pub name: Option<String>,
+    /// host name or ip
+    pub host: String,
+    /// --port when not given
+    pub port: Option<u16>,
+    pub interval: Option<Duration>,
+    pub info_threshold: Option<Duration>,
+    pub warn_threshold: Option<Duration>,
+}
+
+impl FromStr for TargetSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(',');
+        let first = parts.next().unwrap_or_default();
+        let (name, addr) = match first.split_once('=') {
+            Some((name, addr)) if !name.is_empty() => (Some(name.to_string()), addr),
+            Some(_) => return Err(anyhow!("target \"{}\" has an empty name before =", s)),
+            None => (None, first),
+        };
+        let (host, port) = host_port_from_str(addr)?;
+        let mut spec = TargetSpec {
+            name,
+            host,
+            port,
+            interval: None,
+            info_threshold: None,
+            warn_threshold: None,
+        };
+        for part in parts {
+            let (key, value) = part.split_once('=')
+                .ok_or_else(|| anyhow!("target \"{}\" option \"{}\" must be key=value", s, part))?;
+            let value = Some(dur_from_str(value)?);
+            match key.replace('_', "-").as_str() {
+                "interval" => spec.interval = value,
+                "info-threshold" => spec.info_threshold = value,
+                "warn-threshold" => spec.warn_threshold = value,
+                _ => return Err(anyhow!("target \"{}\" option \"{}\" is not one of interval, info-threshold or warn-threshold", s, key)),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+impl TargetSpec {
+    pub fn host_port(&self, default_port: u16) -> HostPort {
+        HostPort { host: self.host.clone(), port: self.port.unwrap_or(default_port) }
+    }
+
+    /// client options for this target - its own overrides on top of `cli`
+    pub fn apply(&self, cli: &ClientOpts) -> ClientOpts {
+        let mut cli = cli.clone();
+        if self.interval.is_some() {
+            cli.interval = self.interval;
+        }
+        if let Some(t) = self.info_threshold {
+            cli.info_threshold = t;
+        }
+        if let Some(t) = self.warn_threshold {
+            cli.warn_threshold = t;
+        }
+        cli
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(s: &str) -> TargetSpec {
+        s.parse().unwrap_or_else(|e| panic!("{}: {}", s, e))
+    }
+
+    #[test]
+    fn name_host_and_port() {
+        let t = spec("host");
+        assert_eq!((t.name, t.host.as_str(), t.port), (None, "host", None));
+        let t = spec("web=host:5151");
+        assert_eq!((t.name.as_deref(), t.host.as_str(), t.port), (Some("web"), "host", Some(5151)));
+        let t = spec("v6=[::1]:80");
+        assert_eq!((t.name.as_deref(), t.host.as_str(), t.port), (Some("v6"), "::1", Some(80)));
+        assert_eq!(spec("::1").host, "::1");
+    }
+
+    #[test]
+    fn options_override_only_what_is_given() {
+        let t = spec("web=host,interval=500ms,warn_threshold=1s");
+        assert_eq!(t.interval, Some(Duration::from_millis(500)));
+        assert_eq!(t.info_threshold, None);
+        assert_eq!(t.warn_threshold, Some(Duration::from_secs(1)));
+        let t = spec("host:80,info-threshold=20ms,interval=1s,interval=2s");
+        assert_eq!(t.info_threshold, Some(Duration::from_millis(20)));
+        assert_eq!(t.interval, Some(Duration::from_secs(2)));
+        let hp = t.host_port(5151);
+        assert_eq!((hp.host.as_str(), hp.port), ("host", 80));
+        assert_eq!(spec("host").host_port(5151).port, 5151);
+    }
+
+    #[test]
+    fn errors() {
+        for s in ["", "=host", "web=", "web=host:", "web=:80", "host,interval", "host,interval=",
+                  "host,interval=1x", "host,timeout=1s", "host,", "host,=1s"] {
+            assert!(s.parse::<TargetSpec>().is_err(), "{} parsed", s);
+        }
+    }
+}
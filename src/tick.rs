@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use anyhow::Context;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::warn;
+use serde::Serialize;
+
+use crate::cli::ClientOpts;
+use crate::otlp::OtlpExporter;
+use crate::outage::Cause;
+use crate::push::{self, Pusher};
+use crate::sink::{Record, Sink, csv_field};
+use crate::stat::{Stat, StatSnapshot};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// where every ticker interval of each target goes besides the log
+///
+/// the file and collector connection are shared by all targets - only OTLP, whose
+/// resource is the target, has an exporter per target.  Collectors are sent to from a
+/// thread of their own so one that does not answer never holds up the ticker
+pub struct Outputs {
+    tick_out: Option<Sink>,
+    push: Option<Worker<(String, StatSnapshot, Duration)>>,
+    otlp: Option<Worker<(String, StatSnapshot)>>,
+}
+
+impl Outputs {
+    pub fn open(cli: &ClientOpts, targets: &[String]) -> Result<Outputs> {
+        let client_name = cli.client_name.clone().unwrap_or_else(push::default_client_name);
+        let push = match cli.push_addr {
+            Some(addr) => {
+                let mut pusher = Pusher::new(addr, cli.push_format, cli.push_proto, &client_name, cli.timeout_socket)?;
+                Some(Worker::spawn("push", targets.len(), move |(target, snap, elapsed): (String, StatSnapshot, Duration)| {
+                    pusher.push(&target, &snap, elapsed)
+                })?)
+            },
+            None => None,
+        };
+        let otlp = match cli.otlp_endpoint {
+            Some(ref ep) => {
+                let exporters: BTreeMap<String, OtlpExporter> = targets.iter()
+                    .map(|t| (t.clone(), OtlpExporter::new(ep.clone(), &client_name, t, cli.timeout_socket)))
+                    .collect();
+                Some(Worker::spawn("otlp", targets.len(), move |(target, snap): (String, StatSnapshot)| {
+                    if let Some(otlp) = exporters.get(&target) {
+                        otlp.export(&snap);
+                    }
+                })?)
+            },
+            None => None,
+        };
+        let outputs = Outputs {
+            tick_out: cli.tick_out.as_ref().map(|p| Sink::open(p, cli.tick_format)).transpose()?,
+            push,
+            otlp,
+        };
+        if outputs.is_some() && cli.ticker_interval.is_none() {
+            warn!("--tick-out, --push-addr and --otlp-endpoint have nothing to send without --ticker-interval");
+        }
+        Ok(outputs)
+    }
+
+    fn is_some(&self) -> bool {
+        self.tick_out.is_some() || self.push.is_some() || self.otlp.is_some()
+    }
+
+    /// the interval from `start` to `end` of `target` that `snap` covers - OTLP gets the running totals from `stat` instead
+    pub fn send(&mut self, target: &str, start: DateTime<Utc>, end: DateTime<Utc>, elapsed: Duration, snap: &StatSnapshot, stat: &Stat) {
+        if let Some(ref sink) = self.tick_out {
+            write(sink, target, start, end, elapsed, snap);
+        }
+        if let Some(ref push) = self.push {
+            push.send((target.to_string(), snap.clone(), elapsed));
+        }
+        if let Some(ref otlp) = self.otlp {
+            otlp.send((target.to_string(), stat.summary()));
+        }
+    }
+
+    /// wait for what was handed to the collectors to be sent - after the last interval
+    pub fn close(self) {
+        let deadline = Instant::now() + CLOSE_WAIT;
+        if let Some(push) = self.push {
+            push.close(deadline);
+        }
+        if let Some(otlp) = self.otlp {
+            otlp.close(deadline);
+        }
+    }
+}
+
+/// how long the end of a run waits for the collectors to take the last intervals
+const CLOSE_WAIT: Duration = Duration::from_secs(2);
+
+/// a thread doing `work` on what it is sent, in order
+///
+/// at most `capacity` items wait for it - more are dropped rather than block the sender
+struct Worker<T> {
+    name: &'static str,
+    tx: SyncSender<T>,
+    thread: JoinHandle<()>,
+}
+
+impl<T: Send + 'static> Worker<T> {
+    fn spawn(name: &'static str, capacity: usize, mut work: impl FnMut(T) + Send + 'static) -> Result<Worker<T>> {
+        let (tx, rx) = sync_channel::<T>(capacity);
+        let thread = std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || rx.iter().for_each(&mut work))
+            .with_context(|| format!("spawning {} thread", name))?;
+        Ok(Worker { name, tx, thread })
+    }
+
+    fn send(&self, item: T) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(item) {
+            warn!("{} collector is not keeping up - dropping an interval", self.name);
+        }
+    }
+
+    /// send what is waiting, giving up at `deadline` so a collector that does not answer
+    /// does not hold up the end of the run either
+    fn close(self, deadline: Instant) {
+        drop(self.tx);
+        while !self.thread.is_finished() {
+            if Instant::now() >= deadline {
+                warn!("{} collector is not answering - giving up on the last intervals", self.name);
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        if self.thread.join().is_err() {
+            warn!("{} thread panicked", self.name);
+        }
+    }
+}
+
+/// one line of --tick-out - the same interval a ticker log line covers
+#[derive(Serialize)]
+struct TickRecord<'a> {
+    /// RFC 3339 UTC start and end of the interval
+    start: String,
+    end: String,
+    target: &'a str,
+    sent: u64,
+    echos: u64,
+    /// echos per second over the interval
+    rate: f64,
+    /// echo times in nanoseconds - empty when no echo came back in the interval
+    min_ns: Option<u64>,
+    avg_ns: Option<u64>,
+    max_ns: Option<u64>,
+    p50_ns: Option<u64>,
+    p90_ns: Option<u64>,
+    p99_ns: Option<u64>,
+    p999_ns: Option<u64>,
+    stddev_ns: Option<u64>,
+    lost: u64,
+    late: u64,
+    /// percent of echos sent in the interval that never came back
+    loss_pct: f64,
+    /// outages that ended in the interval or are still going on
+    outages: u64,
+    /// percent of the run so far not spent in an outage
+    availability_pct: f64,
+    /// connection failures in the interval by cause
+    refused: u64,
+    reset: u64,
+    timeout: u64,
+    dns: u64,
+    protocol: u64,
+    unreachable: u64,
+    other: u64,
+}
+
+fn opt_str<T: ToString>(v: Option<T>) -> String {
+    v.map(|v| v.to_string()).unwrap_or_default()
+}
+
+impl Record for TickRecord<'_> {
+    fn csv_header() -> &'static str {
+        "start,end,target,sent,echos,rate,min_ns,avg_ns,max_ns,p50_ns,p90_ns,p99_ns,p999_ns,stddev_ns,lost,late,loss_pct,outages,availability_pct,refused,reset,timeout,dns,protocol,unreachable,other"
+    }
+
+    fn csv_row(&self) -> String {
+        format!("{},{},{},{},{},{:.3},{},{},{},{},{},{},{},{},{},{},{:.3},{},{:.3},{},{},{},{},{},{},{}"
+                , self.start, self.end, csv_field(self.target), self.sent, self.echos, self.rate
+                , opt_str(self.min_ns), opt_str(self.avg_ns), opt_str(self.max_ns)
+                , opt_str(self.p50_ns), opt_str(self.p90_ns), opt_str(self.p99_ns), opt_str(self.p999_ns)
+                , opt_str(self.stddev_ns), self.lost, self.late, self.loss_pct, self.outages, self.availability_pct
+                , self.refused, self.reset, self.timeout, self.dns, self.protocol, self.unreachable, self.other)
+    }
+}
+
+/// write the ticker interval from `start` to `end` that `snap` covers as one record
+fn write(sink: &Sink, target: &str, start: DateTime<Utc>, end: DateTime<Utc>, elapsed: Duration, snap: &StatSnapshot) {
+    let nanos = |d: Duration| d.as_nanos() as u64;
+    let has_echos = snap.echos > 0;
+    let when = |d: Duration| if has_echos { Some(nanos(d)) } else { None };
+    let rec = TickRecord {
+        start: start.to_rfc3339_opts(SecondsFormat::Millis, true),
+        end: end.to_rfc3339_opts(SecondsFormat::Millis, true),
+        target,
+        sent: snap.sent,
+        echos: snap.echos,
+        rate: snap.echos as f64 / elapsed.as_secs_f64(),
+        min_ns: when(snap.min_time),
+        avg_ns: snap.avg_time().map(nanos),
+        max_ns: when(snap.max_time),
+        p50_ns: when(snap.percentile(0.50)),
+        p90_ns: when(snap.percentile(0.90)),
+        p99_ns: when(snap.percentile(0.99)),
+        p999_ns: when(snap.percentile(0.999)),
+        stddev_ns: when(snap.stddev()),
+        lost: snap.lost,
+        late: snap.late,
+        loss_pct: snap.loss_pct(),
+        outages: snap.outages,
+        availability_pct: snap.availability_pct,
+        refused: snap.failures(Cause::Refused),
+        reset: snap.failures(Cause::Reset),
+        timeout: snap.failures(Cause::Timeout),
+        dns: snap.failures(Cause::Dns),
+        protocol: snap.failures(Cause::Protocol),
+        unreachable: snap.failures(Cause::Unreachable),
+        other: snap.failures(Cause::Other),
+    };
+    if let Err(e) = sink.write(&rec) {
+        warn!("unable to write tick record: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+    use super::*;
+
+    #[test]
+    fn stuck_worker_never_blocks_the_sender() {
+        let (started_tx, started) = channel();
+        let (release, released) = channel::<()>();
+        let done = Arc::new(Mutex::new(vec![]));
+        let done_worker = done.clone();
+        let worker = Worker::spawn("test", 2, move |n: u32| {
+            started_tx.send(n).unwrap();
+            // the first item hangs like a collector that does not answer
+            if n == 0 {
+                released.recv().unwrap();
+            }
+            done_worker.lock().unwrap().push(n);
+        }).unwrap();
+
+        worker.send(0);
+        assert_eq!(started.recv().unwrap(), 0);
+        let sending = Instant::now();
+        for n in 1..10 {
+            worker.send(n);
+        }
+        assert!(sending.elapsed() < Duration::from_secs(1));
+        release.send(()).unwrap();
+        worker.close(Instant::now() + CLOSE_WAIT);
+        assert_eq!(*done.lock().unwrap(), vec![0, 1, 2]);
+    }
+}
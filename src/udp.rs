@@ -0,0 +1,230 @@
+use std::net::{UdpSocket, SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Context};
+use bincode::Options;
+use log::{debug, info, warn};
+
+use crate::cli::{ClientOpts, ServerOpts};
+use crate::stat::Stat;
+use crate::{TimePacket, echo_codec, in_flight_lost, log_echo, record_one_way, single_line_error};
+use crate::seq::{SeqTracker, Arrival};
+use crate::limit::Limit;
+use crate::peers::Peers;
+use crate::dial::HostPort;
+use crate::session::Session;
+use crate::{shutdown, SHUTDOWN_POLL};
+
+type Result<T> = anyhow::Result<T, anyhow::Error>;
+
+/// plenty for a TimePacket but also the largest udp payload possible
+const MAX_DATAGRAM: usize = 65536;
+/// largest payload an IPv4 udp datagram can carry
+pub const MAX_UDP_PAYLOAD: usize = 65507;
+/// how often a client whose echos come back looks its target up again - a DNS failover
+/// is followed even if the old address still answers
+const RESOLVE_EVERY: Duration = Duration::from_secs(60);
+/// how often a client losing echos looks its target up again
+const RESOLVE_LOSING: Duration = Duration::from_secs(1);
+
+/// error unless an echo padded with `payload_size` bytes fits in one datagram - checked against
+/// the server's reply, which carries every timestamp
+pub fn check_payload_size(payload_size: usize) -> Result<()> {
+    let mut tp = TimePacket::new(0, payload_size);
+    tp.server_recv = Some(0);
+    tp.server_send = Some(0);
+    tp.client_recv = Some(0);
+    let len = echo_codec().serialized_size(&tp).context("sizing udp echo")? as usize;
+    if len > MAX_UDP_PAYLOAD {
+        return Err(anyhow!("echo of {} bytes with payload size {} does not fit in a udp datagram of at most {} bytes", len, payload_size, MAX_UDP_PAYLOAD));
+    }
+    Ok(())
+}
+
+/// udp echo server - one socket serves every client so no thread per client here
+pub fn server(socket: UdpSocket, opts: &ServerOpts, peers: &Peers) -> Result<()> {
+    // wake up now and then to notice a shutdown request
+    socket.set_read_timeout(Some(SHUTDOWN_POLL)).context("setting read timeout of server socket")?;
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    while !shutdown::requested() {
+        let (len, client_addr) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => {
+                // on some platforms an ICMP port unreachable from a client that went away shows up here
+                warn!("udp server receive error: {}", e);
+                continue;
+            }
+        };
+        let mut tp: TimePacket = match echo_codec().deserialize(&buf[..len]) {
+            Ok(tp) => tp,
+            Err(e) => {
+                warn!("with client IP {} bad datagram of {} bytes: {}", client_addr, len, e);
+                continue;
+            }
+        };
+        tp.server_recv = Some(crate::util::unix_nanos());
+        tp.server_send = Some(crate::util::unix_nanos());
+        let out = echo_codec().serialize(&tp).context("serializing udp reply")?;
+        if let Err(e) = socket.send_to(&out, client_addr) {
+            warn!("with client IP {} at write: {}", client_addr, e);
+            continue;
+        }
+        peers.echo(&client_addr.to_string(), &client_addr, out.len());
+        debug!("Packet sent to {} {:#?}", client_addr, &tp);
+    }
+    Ok(())
+}
+
+/// a socket connected to the first of `addrs` this host has a route to and the address that was
+///
+/// there is no handshake to race so unlike tcp the addresses are only tried in turn - `timeout`
+/// only bounds the socket's reads and writes
+pub fn build_client_socket(_cli: &ClientOpts, addrs: &[SocketAddr], timeout: Duration) -> Result<(UdpSocket, SocketAddr)> {
+    let mut errors: Vec<String> = vec![];
+    let mut first: Option<anyhow::Error> = None;
+    for socker_addr in addrs.iter() {
+        match connect_client_socket(socker_addr, timeout) {
+            Ok(socket) => return Ok((socket, *socker_addr)),
+            Err(e) => {
+                errors.push(single_line_error(&e));
+                first.get_or_insert(e);
+            },
+        }
+    }
+    let msg = format!("unable to use any of {}", errors.join(", "));
+    Err(match first {
+        Some(e) => e.context(msg),
+        None => anyhow!(msg),
+    })
+}
+
+fn connect_client_socket(socker_addr: &SocketAddr, timeout: Duration) -> Result<UdpSocket> {
+    let local: IpAddr = if socker_addr.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0)).context("binding local udp socket")?;
+    socket.connect(socker_addr).with_context(|| format!("setting udp peer to {}", socker_addr))?;
+    socket.set_read_timeout(Some(timeout)).context("setting read timeout of client socket")?;
+    socket.set_write_timeout(Some(timeout)).context("setting write timeout of client socket")?;
+    Ok(socket)
+}
+
+/// the addresses `dest` resolves to now if `current` is no longer one of them
+fn moved(dest: &HostPort, current: &SocketAddr) -> Option<Vec<SocketAddr>> {
+    match dest.resolve() {
+        Ok(addrs) if !addrs.contains(current) => Some(addrs),
+        Ok(_) => None,
+        Err(e) => {
+            debug!("looking {} up again: {}", dest, single_line_error(&e));
+            None
+        },
+    }
+}
+
+/// echo `payload_size` padded datagrams to `dest` until `limit` says the run is over
+///
+/// there is no connection to lose so `dest` is looked up again now and then, more often while
+/// echos are lost, and the echos follow it when it moves
+pub fn client(mut socket: UdpSocket, cli: &ClientOpts, dest: &HostPort, mut stat: Stat, payload_size: usize, limit: &mut Limit, session: &Session) -> Result<()> {
+    let mut server_addr = socket.peer_addr().context("Unable to get peer_address of udp socket")?;
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let mut seqs = SeqTracker::new();
+    let mut lost_in_row = 0;
+    let mut resolved_at = Instant::now();
+    loop {
+        if !limit.take() {
+            return Ok(());
+        }
+        let resolve_every = if lost_in_row > 0 { RESOLVE_LOSING } else { RESOLVE_EVERY };
+        if resolved_at.elapsed() >= resolve_every {
+            resolved_at = Instant::now();
+            if let Some(addrs) = moved(dest, &server_addr) {
+                let (moved_socket, moved_addr) = build_client_socket(cli, &addrs, cli.timeout_socket)?;
+                info!("{} no longer resolves to {} - echoing to {} instead", dest, server_addr, moved_addr);
+                socket = moved_socket;
+                server_addr = moved_addr;
+            }
+        }
+        let tp_sent = TimePacket::new(seqs.next(), payload_size);
+        let out = echo_codec().serialize(&tp_sent).context("serializing udp echo")?;
+        if out.len() > MAX_UDP_PAYLOAD {
+            return Err(anyhow!("echo of {} bytes with payload size {} does not fit in a udp datagram of at most {} bytes", out.len(), payload_size, MAX_UDP_PAYLOAD));
+        }
+        let sent_at = Instant::now();
+        socket.send(&out).with_context(|| format!("with IP server {} at write", server_addr))?;
+        stat.sent();
+
+        // keep reading until our own echo comes back - anything older is late
+        let deadline = Instant::now() + limit.cap(cli.timeout_socket);
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if shutdown::requested() || limit.expired() {
+                return Ok(());
+            }
+            if remaining == Duration::from_secs(0) {
+                stat.lost();
+                session.echo(tp_sent.seq, None);
+                info!("echo seq {} lost - no reply from {} within {:?}", tp_sent.seq, server_addr, cli.timeout_socket);
+                lost_in_row += 1;
+                if cli.reconnect_after.is_some_and(|max| lost_in_row >= max) {
+                    let e = std::io::Error::new(ErrorKind::TimedOut, format!("no reply to echo seq {} within {:?}", tp_sent.seq, cli.timeout_socket));
+                    return Err(e).with_context(|| format!("with IP server {} at read - {} echos in a row lost", server_addr, lost_in_row));
+                }
+                break;
+            }
+            socket.set_read_timeout(Some(remaining.min(SHUTDOWN_POLL))).context("setting read timeout of client socket")?;
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    let e = anyhow::Error::from(e).context(format!("with IP server {} at read", server_addr));
+                    return Err(in_flight_lost(&mut stat, session, tp_sent.seq, e));
+                },
+            };
+            let mut tp_recv: TimePacket = match echo_codec().deserialize(&buf[..len]).with_context(|| format!("with IP server {} at read", server_addr)) {
+                Ok(tp_recv) => tp_recv,
+                Err(e) => return Err(in_flight_lost(&mut stat, session, tp_sent.seq, e)),
+            };
+            let arrival = seqs.arrive(tp_recv.seq);
+            stat.arrival(arrival);
+            if tp_recv.seq == tp_sent.seq && arrival != Arrival::Duplicate {
+                lost_in_row = 0;
+                let dur = sent_at.elapsed();
+                tp_recv.client_recv = Some(crate::util::unix_nanos());
+                if let Some(outage) = stat.update(dur) {
+                    session.outage(&outage);
+                }
+                session.echo(tp_recv.seq, Some(dur));
+                record_one_way(&mut stat, &tp_recv);
+                log_echo(cli, &session.target, &dur);
+                break;
+            } else if tp_recv.seq < tp_sent.seq && arrival != Arrival::Duplicate {
+                stat.late();
+                debug!("late reply seq {} while waiting on seq {}", tp_recv.seq, tp_sent.seq);
+            } else {
+                debug!("discarding reply seq {} while waiting on seq {} - {:?}", tp_recv.seq, tp_sent.seq, arrival);
+            }
+        }
+        if let Some(ref dur) = cli.interval {
+            limit.sleep_until_even_interval(dur);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moved_only_when_current_address_is_gone() {
+        let dest = HostPort { host: "127.0.0.1".to_string(), port: 5150 };
+        assert!(moved(&dest, &"127.0.0.1:5150".parse().unwrap()).is_none());
+        assert_eq!(moved(&dest, &"127.0.0.2:5150".parse().unwrap()), Some(vec!["127.0.0.1:5150".parse().unwrap()]));
+        // a lookup that fails is no reason to leave an address
+        let dest = HostPort { host: "no-such-host.invalid".to_string(), port: 5150 };
+        assert!(moved(&dest, &"127.0.0.1:5150".parse().unwrap()).is_none());
+    }
+}
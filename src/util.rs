@@ -5,12 +5,13 @@ use chrono::Utc;
 use log::LevelFilter;
 use std::time::{Duration, Instant, SystemTime};
 use anyhow::{anyhow,Context};
-use std::net::{ToSocketAddrs,SocketAddr};
+use std::net::{ToSocketAddrs,SocketAddr,IpAddr,Ipv4Addr,Ipv6Addr};
 use std::str::FromStr;
 use log::{debug, error, info, trace, warn};
 
 use crate::cli::Cli;
-use log4rs::append::console::ConsoleAppender;
+use log4rs::append::console::{ConsoleAppender, Target};
+use std::sync::atomic::{AtomicBool, Ordering};
 use log4rs::Config;
 use log4rs::config::{Appender, Root};
 use log4rs::encode::pattern::PatternEncoder;
@@ -39,17 +40,37 @@ pub fn init_log_env(level: LevelFilter) {
     builder.init();
 }
 */
+/// end of run reports and console logging go to stderr when stdout carries records
+static REPORT_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn report_to_stderr() -> bool {
+    REPORT_TO_STDERR.load(Ordering::Relaxed)
+}
+
+/// println! for summaries and other end of run reports - stderr once a --*-out - has stdout
+macro_rules! report {
+    ($($arg:tt)*) => {
+        if $crate::util::report_to_stderr() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
 pub fn init_log(cli: &Cli) -> Result<()> {
+    REPORT_TO_STDERR.store(cli.records_to_stdout(), Ordering::Relaxed);
     if let Some(ref log_config) = cli.log_config {
         log4rs::init_file(log_config,Default::default())?;
-        println!("setup config for logging with {}", log_config.to_str().unwrap());
+        report!("setup config for logging with {}", log_config.to_str().unwrap());
         info!("START with log configured from {}", log_config.to_str().unwrap());
         Ok(())
     } else {
-        println!("using default console logging");
+        report!("using default console logging");
         let log_level = cli.log_level.unwrap_or(LevelFilter::Info);
         let pat = Box::new(PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S%.3f %Z)(utc)} [{l:<5}] {f}:{L} - {m} {n}"));
-        let stdout = ConsoleAppender::builder().encoder(pat).build();
+        let target = if report_to_stderr() { Target::Stderr } else { Target::Stdout };
+        let stdout = ConsoleAppender::builder().encoder(pat).target(target).build();
         let config = Config::builder()
             .appender(Appender::builder().build("stdout", Box::new(stdout)))
             .build(Root::builder().appender("stdout").build(log_level))
@@ -75,7 +96,7 @@ pub fn to_duration(s: &str) -> Result<Duration> {
     let mut num = String::new();
     let mut sum_secs = 0u64;
     for c in s.chars() {
-        if c >= '0' && c <='9' {
+        if c.is_ascii_digit() {
             num.push(c);
         } else {
             let s = num.parse::<u64>().with_context(|| format!("cannot parse number {} inside duration {}", &num, &s))?;
@@ -90,7 +111,7 @@ pub fn to_duration(s: &str) -> Result<Duration> {
             }
         }
     }
-    if num.len() > 0 {
+    if !num.is_empty() {
         sum_secs += num.parse::<u64>().with_context(|| format!("cannot parse number {} inside duration {}", &num, &s))?;
     }
     Ok(Duration::from_secs(sum_secs))
@@ -114,7 +135,7 @@ pub fn greek(v: f64) -> String {
     }
     if s.len() < 4 { s.push(' '); }
 
-    return format!("{:<5}{}", s, GREEK_SUFFIXES[multi]);
+    format!("{:<5}{}", s, GREEK_SUFFIXES[multi])
 }
 
 
@@ -122,7 +143,7 @@ pub fn to_size_u64(s: &str) -> Result<u64> {
     let mut num = String::new();
     let mut bytes = 0u64;
     for c in s.chars() {
-        if c >= '0' && c <='9' {
+        if c.is_ascii_digit() {
             num.push(c);
         } else {
             let s = num.parse::<u64>().with_context(|| format!("cannot parse number {} inside duration {}", &num, &s))?;
@@ -137,7 +158,7 @@ pub fn to_size_u64(s: &str) -> Result<u64> {
             }
         }
     }
-    if num.len() > 0 {
+    if !num.is_empty() {
         bytes += num.parse::<u64>().with_context(|| format!("cannot parse number {} inside size {}", &num, &s))?;
     }
     Ok(bytes)
@@ -145,7 +166,7 @@ pub fn to_size_u64(s: &str) -> Result<u64> {
 
 pub fn to_size_usize(s: &str) -> Result<usize> {
     let sz = to_size_u64(s)?;
-    return Ok(sz as usize);
+    Ok(sz as usize)
 }
 
 pub fn str_to_socketaddr(s: &str) -> Result<SocketAddr> {
@@ -183,5 +204,23 @@ pub fn compute_until_even_interval_nanos(now: Option<&SystemTime>, interval: &Du
         .expect("cannot get system time")
         .as_nanos();// / dur.as_nanos();
 
-    Duration::from_nanos((((now_nanos / interval.as_nanos() + 1) *  interval.as_nanos() - now_nanos) as u64))
-}
\ No newline at end of file
+    Duration::from_nanos(((now_nanos / interval.as_nanos() + 1) *  interval.as_nanos() - now_nanos) as u64)
+}
+
+/// wall-clock time as nanoseconds since the UNIX epoch - what goes on the wire
+pub fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("cannot get system time")
+        .as_nanos() as u64
+}
+
+/// where to connect to reach a listener bound to `addr` - loopback in place of a wildcard
+pub fn wake_addr(addr: &SocketAddr) -> SocketAddr {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    SocketAddr::new(ip, addr.port())
+}